@@ -0,0 +1,236 @@
+use std::fs;
+use std::env;
+use std::io::{self, Write};
+use std::path;
+use std::time;
+
+use bytes::Bytes;
+use serde::{Serialize, Deserialize};
+
+use crate::error;
+
+const FNV_OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// A cache of responses on disk. Each response is stored in its own file,
+/// named for the hash of its request, which begins with a line describing
+/// the response and is followed by its body.
+#[derive(Debug, Clone)]
+pub struct Cache {
+  dir: path::PathBuf,
+}
+
+/// A cached response.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+  stored: u64,
+  ttl: u64,
+  pub etag: Option<String>,
+  pub last_modified: Option<String>,
+  pub content_type: Option<String>,
+  #[serde(skip)]
+  pub data: Bytes,
+}
+
+impl Entry {
+  pub fn new(ttl: time::Duration, etag: Option<String>, last_modified: Option<String>, content_type: Option<String>, data: Bytes) -> Self {
+    Self{
+      stored: unix_time(time::SystemTime::now()),
+      ttl: ttl.as_secs(),
+      etag,
+      last_modified,
+      content_type,
+      data,
+    }
+  }
+
+  /// Determine whether the entry can be used without asking the service
+  /// whether it has changed.
+  pub fn is_fresh(&self, now: time::SystemTime) -> bool {
+    unix_time(now) < self.stored.saturating_add(self.ttl)
+  }
+
+  /// Determine whether the service can be asked whether the entry has changed.
+  pub fn can_revalidate(&self) -> bool {
+    self.etag.is_some() || self.last_modified.is_some()
+  }
+
+  /// The same entry, stored again now.
+  pub fn renew(self) -> Self {
+    Self{
+      stored: unix_time(time::SystemTime::now()),
+      ..self
+    }
+  }
+}
+
+impl Cache {
+  pub fn new<P: AsRef<path::Path>>(dir: P) -> Self {
+    Self{
+      dir: dir.as_ref().to_path_buf(),
+    }
+  }
+
+  /// The cache in the user's cache directory, which is `$XDG_CACHE_HOME`
+  /// or `~/.cache`.
+  pub fn default_dir() -> Option<path::PathBuf> {
+    match env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+      Some(dir) => Some(path::PathBuf::from(dir).join("unfurl")),
+      None      => env::home_dir().map(|home| home.join(".cache").join("unfurl")),
+    }
+  }
+
+  pub fn get(&self, key: &str) -> Option<Entry> {
+    let data = fs::read(self.path(key)).ok()?;
+    let n = data.iter().position(|c| *c == b'\n')?;
+    let mut entry: Entry = serde_json::from_slice(&data[..n]).ok()?;
+    entry.data = Bytes::copy_from_slice(&data[n + 1..]);
+    Some(entry)
+  }
+
+  pub fn put(&self, key: &str, entry: &Entry) -> Result<(), error::Error> {
+    create_dir(&self.dir)?;
+    // write elsewhere first, so that the entry is never read half-written
+    let tmp = self.dir.join(format!(".{}.{}", key, std::process::id()));
+    let mut file = create_file(&tmp)?;
+    serde_json::to_writer(&mut file, entry)?;
+    file.write_all(b"\n")?;
+    file.write_all(&entry.data)?;
+    drop(file);
+    fs::rename(&tmp, self.path(key))?;
+    Ok(())
+  }
+
+  /// Remove every entry from the cache.
+  pub fn clear(&self) -> Result<(), error::Error> {
+    match fs::remove_dir_all(&self.dir) {
+      Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+      _                                                 => Ok(()),
+    }
+  }
+
+  fn path(&self, key: &str) -> path::PathBuf {
+    self.dir.join(key)
+  }
+}
+
+/// The key for a request: a hash of its method, URL, headers and body, and
+/// of a description of the credentials it's made with, if any, so that
+/// requests made with different credentials are cached separately. Headers
+/// marked as sensitive are left out, so that the key stays the same when a
+/// token is refreshed. Only the hash is stored, never the request itself.
+pub fn key(req: &reqwest::Request, credentials: Option<&str>) -> Option<String> {
+  let mut hash = FNV_OFFSET;
+  let mut write = |data: &[u8]| {
+    for b in data.iter().chain(b"\0") {
+      hash = (hash ^ *b as u128).wrapping_mul(FNV_PRIME);
+    }
+  };
+  write(req.method().as_str().as_bytes());
+  write(req.url().as_str().as_bytes());
+  // headers are in the order they were added, which isn't always the same
+  let mut headers: Vec<_> = req.headers().iter().filter(|(_, val)| !val.is_sensitive()).map(|(name, val)| (name.as_str(), val.as_bytes())).collect();
+  headers.sort();
+  for (name, val) in headers {
+    write(name.as_bytes());
+    write(val);
+  }
+  if let Some(body) = req.body() {
+    write(body.as_bytes()?);
+  }
+  if let Some(credentials) = credentials {
+    write(credentials.as_bytes());
+  }
+  Some(format!("{:032x}", hash))
+}
+
+// Cached responses may be private, so only the user can read them.
+#[cfg(unix)]
+fn create_dir(dir: &path::Path) -> io::Result<()> {
+  use std::os::unix::fs::DirBuilderExt;
+  fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)
+}
+
+#[cfg(not(unix))]
+fn create_dir(dir: &path::Path) -> io::Result<()> {
+  fs::create_dir_all(dir)
+}
+
+fn create_file(path: &path::Path) -> io::Result<fs::File> {
+  let mut opts = fs::OpenOptions::new();
+  opts.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    opts.mode(0o600);
+  }
+  opts.open(path)
+}
+
+fn unix_time(t: time::SystemTime) -> u64 {
+  t.duration_since(time::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request(url: &str, headers: &[(&str, &str)]) -> reqwest::Request {
+    let mut req = reqwest::Client::new().get(url);
+    for (name, val) in headers {
+      req = req.header(*name, *val);
+    }
+    req.build().unwrap()
+  }
+
+  #[test]
+  fn request_keys() {
+    let a = key(&request("https://example.com/a", &[("Accept", "text/plain")]), Some("a")).unwrap();
+    assert_eq!(32, a.len());
+    assert_eq!(Some(a.clone()), key(&request("https://example.com/a", &[("Accept", "text/plain")]), Some("a")));
+    assert_ne!(Some(a.clone()), key(&request("https://example.com/a", &[("Accept", "text/html")]), Some("a")));
+    assert_ne!(Some(a.clone()), key(&request("https://example.com/a", &[("Accept", "text/plain")]), Some("b")));
+    assert_ne!(Some(a.clone()), key(&request("https://example.com/a", &[("Accept", "text/plain")]), None));
+    assert_ne!(Some(a.clone()), key(&request("https://example.com/b", &[("Accept", "text/plain")]), Some("a")));
+    assert_eq!(
+      key(&request("https://example.com/a", &[("Accept", "text/plain"), ("X-Version", "1")]), None),
+      key(&request("https://example.com/a", &[("X-Version", "1"), ("Accept", "text/plain")]), None),
+    );
+    let post = |body: &str| key(&reqwest::Client::new().post("https://example.com/a").body(body.to_string()).build().unwrap(), None);
+    assert_ne!(post("1"), post("2"));
+
+    let secret = |token: &str| {
+      let mut val = reqwest::header::HeaderValue::from_str(token).unwrap();
+      val.set_sensitive(true);
+      key(&reqwest::Client::new().get("https://example.com/a").header("Authorization", val).build().unwrap(), Some("a"))
+    };
+    assert_eq!(secret("Bearer 1"), secret("Bearer 2"));
+  }
+
+  #[test]
+  fn store_entries() {
+    let dir = env::temp_dir().join(format!("unfurl-cache-test-{}", std::process::id()));
+    let cache = Cache::new(&dir);
+    assert_eq!(None, cache.get("a"));
+    let entry = Entry::new(time::Duration::from_secs(60), Some("\"v1\"".to_string()), None, Some("application/json".to_string()), Bytes::from("{\"a\":\n1}"));
+    cache.put("a", &entry).unwrap();
+    assert_eq!(Some(entry.clone()), cache.get("a"));
+    #[cfg(unix)]
+    {
+      use std::os::unix::fs::PermissionsExt;
+      assert_eq!(0o700, fs::metadata(&dir).unwrap().permissions().mode() & 0o777);
+      assert_eq!(0o600, fs::metadata(dir.join("a")).unwrap().permissions().mode() & 0o777);
+    }
+
+    let now = time::SystemTime::now();
+    assert!(entry.is_fresh(now));
+    assert!(!entry.is_fresh(now + time::Duration::from_secs(61)));
+    assert!(entry.can_revalidate());
+    assert!(!Entry::new(time::Duration::ZERO, None, None, None, Bytes::new()).is_fresh(now));
+
+    cache.clear().unwrap();
+    assert_eq!(None, cache.get("a"));
+    cache.clear().unwrap();
+  }
+
+}
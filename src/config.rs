@@ -1,7 +1,12 @@
 use std::fs;
-use std::io::Read;
+use std::fmt;
+use std::io::{self, Read};
 use std::env;
 use std::path;
+use std::process;
+use std::thread;
+use std::time;
+use std::sync::{Arc, OnceLock};
 use std::collections::HashMap;
 
 use serde::{Serialize, Deserialize};
@@ -14,8 +19,18 @@ use crate::error;
 pub struct Config {
   #[serde(default = "HashMap::new")]
   services: HashMap<String, Service>,
-  #[serde(skip, default = "Service::new")]
+  #[serde(rename = "default", default = "Service::new")]
   default_service: Service,
+  #[serde(default)]
+  html: Html,
+  #[serde(default)]
+  fetch: Fetch,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Config {
@@ -23,17 +38,51 @@ impl Config {
     Config{
       services: HashMap::new(),
       default_service: Service::new(),
+      html: Html::default(),
+      fetch: Fetch::default(),
     }
   }
 
-  pub fn service<'a>(&'a self, domain: &str) -> Option<&'a Service> {
-    self.services.get(domain)
+  /// The service for a host, which is the one configured for the host
+  /// itself or, failing that, for a domain it belongs to; see `host_names`.
+  pub fn service<'a>(&'a self, host: &str) -> Option<&'a Service> {
+    host_names(host).iter().find_map(|name| self.services.get(name))
+  }
+
+  /// Every service which applies to a host, most specific first, along
+  /// with the name each was configured under.
+  pub fn services_for<'a>(&'a self, host: &str) -> Vec<(&'a str, &'a Service)> {
+    host_names(host).iter()
+      .filter_map(|name| self.services.get_key_value(name))
+      .map(|(name, svc)| (name.as_str(), svc))
+      .collect()
   }
 
   pub fn default_service<'a>(&'a self) -> &'a Service {
     &self.default_service
   }
 
+  pub fn html<'a>(&'a self) -> &'a Html {
+    &self.html
+  }
+
+  pub fn fetch(&self) -> &Fetch {
+    &self.fetch
+  }
+
+  pub fn set_fetch(&mut self, fetch: Fetch) {
+    self.fetch = fetch;
+  }
+
+  /// The fetch settings for a domain, which are its service's settings where
+  /// it has them and the global settings otherwise.
+  pub fn fetch_for(&self, domain: &str) -> Fetch {
+    match self.service(domain) {
+      Some(svc) => svc.fetch.or(&self.fetch),
+      None      => self.fetch.clone(),
+    }
+  }
+
   pub fn service_or_default<'a>(&'a self, domain: &str) -> &'a Service {
     match self.service(domain) {
       Some(svc) => svc,
@@ -42,6 +91,43 @@ impl Config {
   }
 }
 
+/// The names under which settings for a host may be given, most specific
+/// first: the host itself, wildcards for each of the domains it belongs to
+/// down to its root domain, and finally the root domain itself. For
+/// `a.treno.atlassian.net` these are `a.treno.atlassian.net`,
+/// `*.treno.atlassian.net`, `*.atlassian.net` and `atlassian.net`.
+pub fn host_names(host: &str) -> Vec<String> {
+  let mut names = vec![host.to_string()];
+  let root = match root_domain(host) {
+    Some(root) => root,
+    None       => return names,
+  };
+  let mut rest = host;
+  while let Some((_, parent)) = rest.split_once('.') {
+    if parent.len() < root.len() {
+      break;
+    }
+    names.push(format!("*.{}", parent));
+    rest = parent;
+  }
+  if root != host {
+    names.push(root.to_string());
+  }
+  names
+}
+
+/// The registrable domain a host belongs to, like `atlassian.net` for
+/// `treno.atlassian.net`. Addresses have none.
+pub fn root_domain(host: &str) -> Option<&str> {
+  if host.parse::<std::net::IpAddr>().is_ok() {
+    return None;
+  }
+  match addr::parse_domain_name(host) {
+    Ok(addr) => Some(addr.root().unwrap_or(host)), // weird; just use the input host
+    Err(_)   => None,
+  }
+}
+
 pub fn load<P: AsRef<path::Path>>(p: &Option<P>) -> Result<Config, error::Error> {
   match p {
     Some(p) => load_data(fs::File::open(p)?),
@@ -49,10 +135,16 @@ pub fn load<P: AsRef<path::Path>>(p: &Option<P>) -> Result<Config, error::Error>
   }
 }
 
+/// Load the configuration in the user's home directory, if there is one.
 pub fn load_default() -> Result<Config, error::Error> {
-  match env::home_dir() {
-    Some(home) => load_data(fs::File::open(home.join(".unfurl.yml"))?),
-    None       => Err(error::Error::NotFound),
+  let home = match env::home_dir() {
+    Some(home) => home,
+    None       => return Err(error::Error::NotFound),
+  };
+  match fs::File::open(home.join(".unfurl.yml")) {
+    Ok(file)                                          => load_data(file),
+    Err(err) if err.kind() == io::ErrorKind::NotFound => Err(error::Error::NotFound),
+    Err(err)                                          => Err(err.into()),
   }
 }
 
@@ -63,20 +155,374 @@ pub fn load_data<R: Read>(mut r: R) -> Result<Config, error::Error> {
   Ok(conf)
 }
 
+/// How requests to a service are authenticated.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Authn {
+  /// A token, sent as `Authorization: Bearer <token>`.
+  Bearer(Secret),
+  /// A username and password, sent as `Authorization: Basic ...`.
+  Basic{username: Secret, password: Secret},
+  /// Any header.
+  Header(Header),
+  /// A query parameter.
+  Query{param: String, value: Secret},
+  /// An OAuth2 access token, sent as `Authorization: Bearer <token>`.
+  Oauth2(OAuth2),
+}
+
+/// A header used to authenticate. Given only a value, it's the
+/// `Authorization` header.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Header {
+  Named{name: String, value: Secret},
+  Authorization(Secret),
+}
+
+impl Authn {
+  /// A description of the credentials which identifies them without
+  /// including any secret, or anything which changes when they're renewed.
+  pub fn identity(&self) -> Result<String, error::Error> {
+    Ok(match self {
+      Self::Bearer(_)                        => "bearer".to_string(),
+      Self::Basic{username, ..}              => format!("basic {}", username.resolve()?),
+      Self::Header(Header::Named{name, ..})  => format!("header {}", name.to_lowercase()),
+      Self::Header(Header::Authorization(_)) => "header authorization".to_string(),
+      Self::Query{param, ..}                 => format!("query {}", param),
+      Self::Oauth2(oauth)                    => format!("oauth2 {} {}", oauth.token_url, oauth.client_id.resolve()?),
+    })
+  }
+}
+
+/// Credentials which are exchanged for a short-lived access token at a
+/// token endpoint, using a refresh token when one is given and the client
+/// credentials grant otherwise. The access token is used until it expires,
+/// after which another is requested. Tokens are requested asynchronously,
+/// when a request which needs one is made, rather than when the request is
+/// authenticated; see `Service::oauth2`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OAuth2 {
+  pub token_url: String,
+  pub client_id: Secret,
+  pub client_secret: Secret,
+  pub refresh_token: Option<Secret>,
+  pub scope: Option<String>,
+  #[serde(skip)]
+  token: Arc<tokio::sync::Mutex<Option<AccessToken>>>,
+}
+
+// Tokens are requested a little before they expire, so that they don't
+// expire in flight.
+const OAUTH2_EXPIRY_MARGIN: time::Duration = time::Duration::from_secs(30);
+const OAUTH2_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+// How long a secret command may take before it's killed.
+const SECRET_COMMAND_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+#[derive(Clone)]
+struct AccessToken {
+  value: String,
+  refresh_token: Option<String>,
+  expires: Option<time::Instant>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+  access_token: String,
+  refresh_token: Option<String>,
+  expires_in: Option<u64>,
+}
+
+impl OAuth2 {
+  pub fn new(token_url: &str, client_id: Secret, client_secret: Secret) -> Self {
+    Self{
+      token_url: token_url.to_string(),
+      client_id,
+      client_secret,
+      refresh_token: None,
+      scope: None,
+      token: Arc::new(tokio::sync::Mutex::new(None)),
+    }
+  }
+
+  /// The `Authorization` header for the current access token.
+  pub async fn authorization(&self) -> Result<reqwest::header::HeaderValue, error::Error> {
+    sensitive(&format!("Bearer {}", self.access_token().await?))
+  }
+
+  /// The current access token, requesting a new one if there isn't one or
+  /// it has expired. Only one token is requested at a time.
+  pub async fn access_token(&self) -> Result<String, error::Error> {
+    let mut token = self.token.lock().await;
+    let now = time::Instant::now();
+    if let Some(curr) = token.as_ref() {
+      if curr.expires.is_none_or(|expires| now < expires) {
+        return Ok(curr.value.clone());
+      }
+    }
+    // a service may issue a new refresh token with each access token, in
+    // which case the old one can no longer be used
+    let refresh_token = match token.as_ref().and_then(|curr| curr.refresh_token.clone()) {
+      Some(refresh_token) => Some(refresh_token),
+      None                => match &self.refresh_token {
+        Some(refresh_token) => Some(refresh_token.resolve()?.to_string()),
+        None                => None,
+      },
+    };
+    let next = self.request(refresh_token, now).await?;
+    let value = next.value.clone();
+    *token = Some(next);
+    Ok(value)
+  }
+
+  async fn request(&self, refresh_token: Option<String>, now: time::Instant) -> Result<AccessToken, error::Error> {
+    let mut params = vec![
+      ("client_id", self.client_id.resolve()?.to_string()),
+      ("client_secret", self.client_secret.resolve()?.to_string()),
+    ];
+    match &refresh_token {
+      Some(refresh_token) => {
+        params.push(("grant_type", "refresh_token".to_string()));
+        params.push(("refresh_token", refresh_token.clone()));
+      },
+      None => params.push(("grant_type", "client_credentials".to_string())),
+    };
+    if let Some(scope) = &self.scope {
+      params.push(("scope", scope.clone()));
+    }
+    let client = reqwest::Client::builder().timeout(OAUTH2_TIMEOUT).build()?;
+    let rsp = client.post(&self.token_url).form(&params).send().await?;
+    if !rsp.status().is_success() {
+      return Err(error::Error::Invalid(format!("Could not get an OAuth2 access token: {}", rsp.status())));
+    }
+    let rsp: TokenResponse = rsp.json().await?;
+    Ok(AccessToken{
+      value: rsp.access_token,
+      refresh_token: rsp.refresh_token.or(refresh_token),
+      expires: rsp.expires_in.map(|secs| now + time::Duration::from_secs(secs).saturating_sub(OAUTH2_EXPIRY_MARGIN)),
+    })
+  }
+}
+
+impl PartialEq for OAuth2 {
+  fn eq(&self, other: &Self) -> bool {
+    self.token_url == other.token_url && self.client_id == other.client_id && self.client_secret == other.client_secret && self.refresh_token == other.refresh_token && self.scope == other.scope
+  }
+}
+
+impl fmt::Debug for AccessToken {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "AccessToken(..)")
+  }
+}
+
+/// A secret, like a token. It's given either as a value, in which `${NAME}`
+/// is replaced by the environment variable `NAME`, or as a `command` whose
+/// output is the secret. A secret is resolved when it's first used, at most
+/// once per run, and is never included in debug output.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "Source", into = "Source")]
+pub struct Secret {
+  source: Source,
+  value: Arc<OnceLock<Result<String, String>>>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Source {
+  Value(String),
+  Command{command: String},
+}
+
+impl Secret {
+  pub fn new(value: &str) -> Self {
+    Self::from(Source::Value(value.to_string()))
+  }
+
+  pub fn command(command: &str) -> Self {
+    Self::from(Source::Command{command: command.to_string()})
+  }
+
+  pub fn resolve(&self) -> Result<&str, error::Error> {
+    match self.value.get_or_init(|| self.source.resolve()) {
+      Ok(value) => Ok(value),
+      Err(msg)  => Err(error::Error::Invalid(msg.clone())),
+    }
+  }
+}
+
+impl From<Source> for Secret {
+  fn from(source: Source) -> Self {
+    Self{
+      source,
+      value: Arc::new(OnceLock::new()),
+    }
+  }
+}
+
+impl From<Secret> for Source {
+  fn from(secret: Secret) -> Self {
+    secret.source
+  }
+}
+
+impl PartialEq for Secret {
+  fn eq(&self, other: &Self) -> bool {
+    self.source == other.source
+  }
+}
+
+impl fmt::Debug for Secret {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Secret(..)")
+  }
+}
+
+impl Source {
+  fn resolve(&self) -> Result<String, String> {
+    match self {
+      Self::Value(value)     => interpolate(value, |name| env::var(name).ok()),
+      Self::Command{command} => run(command, SECRET_COMMAND_TIMEOUT),
+    }
+  }
+}
+
+// Replace each `${NAME}` in text with the value of the variable NAME.
+fn interpolate<F: Fn(&str) -> Option<String>>(text: &str, lookup: F) -> Result<String, String> {
+  let mut res = String::new();
+  let mut rest = text;
+  while let Some(start) = rest.find("${") {
+    let end = match rest[start..].find('}') {
+      Some(n) => start + n,
+      None    => break,
+    };
+    let name = &rest[start + 2..end];
+    res.push_str(&rest[..start]);
+    match lookup(name) {
+      Some(val) => res.push_str(&val),
+      None      => return Err(format!("Environment variable is not set: {}", name)),
+    }
+    rest = &rest[end + 1..];
+  }
+  res.push_str(rest);
+  Ok(res)
+}
+
+// Run a command, producing its output without any trailing newline. The
+// command gets no input, so that it can't consume ours, but its errors are
+// written to our standard error. A command which doesn't finish within the
+// timeout, like one waiting on a prompt we can't see, is killed.
+fn run(command: &str, timeout: time::Duration) -> Result<String, String> {
+  let mut child = process::Command::new("sh").arg("-c").arg(command)
+    .stdin(process::Stdio::null())
+    .stdout(process::Stdio::piped())
+    .stderr(process::Stdio::inherit())
+    .spawn()
+    .map_err(|err| format!("Could not run secret command: {}", err))?;
+  // output is read as it's written, so that the command can't block on a
+  // full pipe while we wait for it
+  let mut stdout = child.stdout.take();
+  let reader = thread::spawn(move || {
+    let mut out = Vec::new();
+    if let Some(stdout) = stdout.as_mut() {
+      let _ = stdout.read_to_end(&mut out);
+    }
+    out
+  });
+  let deadline = time::Instant::now() + timeout;
+  let status = loop {
+    match child.try_wait() {
+      Ok(Some(status)) => break status,
+      Ok(None) if time::Instant::now() < deadline => thread::sleep(time::Duration::from_millis(10)),
+      Ok(None) => {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(format!("Secret command timed out after {}s", timeout.as_secs_f64()));
+      },
+      Err(err) => return Err(format!("Could not run secret command: {}", err)),
+    }
+  };
+  if !status.success() {
+    return Err(format!("Secret command failed: {}", status));
+  }
+  let out = reader.join().map_err(|_| "Could not read the output of secret command".to_string())?;
+  match String::from_utf8(out) {
+    Ok(out) => Ok(out.trim_end_matches(['\r', '\n']).to_string()),
+    Err(_)  => Err("Secret command produced invalid text".to_string()),
+  }
+}
+
+/// One or more templates used to format a response. When there are several,
+/// the first one that renders successfully is used.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
-pub struct Authn {
-  pub header: Option<String>,
+#[serde(untagged)]
+pub enum Format {
+  Template(String),
+  Templates(Vec<String>),
+}
+
+impl Format {
+  pub fn templates(&self) -> &[String] {
+    match self {
+      Self::Template(tmpl)  => std::slice::from_ref(tmpl),
+      Self::Templates(tmpl) => tmpl,
+    }
+  }
+}
+
+/// Settings for unfurling links that have no route from the metadata in
+/// their HTML.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Html {
+  #[serde(default)]
+  pub enabled: bool,
+  pub format: Option<Format>,
+}
+
+/// Settings for fetching responses; any which are not set use defaults.
+/// Durations are in seconds.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct Fetch {
+  pub concurrency: Option<usize>,
+  pub connect_timeout: Option<f64>,
+  pub timeout: Option<f64>,
+  pub retries: Option<u32>,
+  pub backoff: Option<f64>,
+  pub max_wait: Option<f64>,
+}
+
+impl Fetch {
+  /// These settings, with any which are not set taken from another.
+  pub fn or(&self, other: &Fetch) -> Fetch {
+    Fetch{
+      concurrency: self.concurrency.or(other.concurrency),
+      connect_timeout: self.connect_timeout.or(other.connect_timeout),
+      timeout: self.timeout.or(other.timeout),
+      retries: self.retries.or(other.retries),
+      backoff: self.backoff.or(other.backoff),
+      max_wait: self.max_wait.or(other.max_wait),
+    }
+  }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Service {
+  #[serde(default, with = "serde_yaml::with::singleton_map")]
   pub auth: Option<Authn>,
-  pub format: Option<HashMap<String, String>>,
+  pub format: Option<HashMap<String, Format>>,
+  #[serde(default)]
+  pub fetch: Fetch,
 }
 
 pub trait Authenticator {
-  fn authenticate(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder;
-  fn authenticate_chain<A: Authenticator>(&self, req: reqwest::RequestBuilder, next: Option<A>) -> reqwest::RequestBuilder;
+  fn authenticate(&self, req: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder, error::Error>;
+  fn authenticate_chain<A: Authenticator>(&self, req: reqwest::RequestBuilder, next: Option<A>) -> Result<reqwest::RequestBuilder, error::Error>;
+}
+
+impl Default for Service {
+  fn default() -> Self {
+    Self::new()
+  }
 }
 
 impl Service {
@@ -84,38 +530,308 @@ impl Service {
     Self{
       auth: None,
       format: None,
+      fetch: Fetch::default(),
+    }
+  }
+
+  /// Identify the credentials requests to the service are made with, if it
+  /// has any; see `Authn::identity`.
+  pub fn credentials(&self) -> Result<Option<String>, error::Error> {
+    self.auth.as_ref().map(Authn::identity).transpose()
+  }
+
+  /// The OAuth2 credentials requests to the service are made with, if it
+  /// has them. Requests aren't given a token when they're authenticated, so
+  /// a token must be added to them when they're made.
+  pub fn oauth2(&self) -> Option<&OAuth2> {
+    match &self.auth {
+      Some(Authn::Oauth2(oauth2)) => Some(oauth2),
+      _                           => None,
     }
   }
 
-  pub fn format<'a>(&'a self, name: &str) -> Option<&'a str> {
+  pub fn format<'a>(&'a self, name: &str) -> Option<&'a Format> {
     match &self.format {
-      Some(format) => format.get(name).map(|x| x.as_str()),
+      Some(format) => format.get(name),
       None         => None,
     }
   }
 }
 
 impl Authenticator for Service {
-  fn authenticate(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+  fn authenticate(&self, req: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder, error::Error> {
     self.authenticate_chain::<Service>(req, None)
   }
 
-  fn authenticate_chain<A: Authenticator>(&self, req: reqwest::RequestBuilder, next: Option<A>) -> reqwest::RequestBuilder {
-    let auth = match &self.auth {
-      Some(auth) => auth,
-      None       => return match next {
-        Some(next) => next.authenticate(req),
-        None       => return req,
+  fn authenticate_chain<A: Authenticator>(&self, req: reqwest::RequestBuilder, next: Option<A>) -> Result<reqwest::RequestBuilder, error::Error> {
+    match (&self.auth, next) {
+      (Some(auth), _)    => auth.authenticate(req),
+      (None, Some(next)) => next.authenticate(req),
+      (None, None)       => Ok(req),
+    }
+  }
+}
+
+impl Authenticator for Authn {
+  fn authenticate(&self, req: reqwest::RequestBuilder) -> Result<reqwest::RequestBuilder, error::Error> {
+    Ok(match self {
+      Self::Bearer(token) => req.header(reqwest::header::AUTHORIZATION, sensitive(&format!("Bearer {}", token.resolve()?))?),
+      Self::Basic{username, password} => req.basic_auth(username.resolve()?, Some(password.resolve()?)),
+      Self::Header(Header::Named{name, value}) => {
+        let name = reqwest::header::HeaderName::from_bytes(name.as_bytes())
+          .map_err(|_| error::Error::Invalid(format!("Invalid credentials: not a valid header name: {}", name)))?;
+        req.header(name, sensitive(value.resolve()?)?)
       },
+      Self::Header(Header::Authorization(value)) => req.header(reqwest::header::AUTHORIZATION, sensitive(value.resolve()?)?),
+      Self::Query{param, value} => req.query(&[(param, value.resolve()?)]),
+      // the token is added when the request is made; see `Service::oauth2`
+      Self::Oauth2(_) => req,
+    })
+  }
+
+  // A service's own credentials are always used; none are taken from others.
+  fn authenticate_chain<A: Authenticator>(&self, req: reqwest::RequestBuilder, _: Option<A>) -> Result<reqwest::RequestBuilder, error::Error> {
+    self.authenticate(req)
+  }
+}
+
+// A header value which is never included in debug output.
+fn sensitive(value: &str) -> Result<reqwest::header::HeaderValue, error::Error> {
+  match reqwest::header::HeaderValue::from_str(value) {
+    Ok(mut value) => {
+      value.set_sensitive(true);
+      Ok(value)
+    },
+    Err(_) => Err(error::Error::Invalid("Invalid credentials: not a valid header value".to_string())),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing;
+  use std::sync::mpsc;
+
+  // Serve each response in turn, returning the token URL and a receiver for
+  // each request.
+  fn serve_tokens(responses: Vec<(u16, &str)>) -> (String, mpsc::Receiver<testing::Received>) {
+    let (addr, rx) = testing::serve(responses.into_iter().map(|(status, data)| testing::json(status, data)).collect());
+    (format!("http://{}/oauth/token", addr), rx)
+  }
+
+  #[test]
+  fn fetch_settings() {
+    let conf = load_data(r#"
+fetch:
+  concurrency: 8
+  timeout: 10
+services:
+  github.com:
+    fetch:
+      concurrency: 2
+      retries: 5
+"#.as_bytes()).unwrap();
+    assert_eq!(Fetch{concurrency: Some(2), timeout: Some(10.0), retries: Some(5), ..Fetch::default()}, conf.fetch_for("github.com"));
+    assert_eq!(Fetch{concurrency: Some(8), timeout: Some(10.0), ..Fetch::default()}, conf.fetch_for("example.com"));
+  }
+
+  #[test]
+  fn interpolate_secrets() {
+    let lookup = |name: &str| match name {
+      "TOKEN" => Some("abc".to_string()),
+      "EMPTY" => Some(String::new()),
+      _       => None,
     };
-    let header = match &auth.header {
-      Some(header) => header,
-      None       => return match next {
-        Some(next) => next.authenticate(req),
-        None       => return req,
-      },
+    assert_eq!(Ok("Bearer abc".to_string()), interpolate("Bearer ${TOKEN}", lookup));
+    assert_eq!(Ok("abc:abc!".to_string()), interpolate("${TOKEN}:${TOKEN}${EMPTY}!", lookup));
+    assert_eq!(Ok("Bearer $TOKEN ${TOKEN".to_string()), interpolate("Bearer $TOKEN ${TOKEN", lookup));
+    assert!(interpolate("Bearer ${MISSING}", lookup).is_err());
+  }
+
+  #[test]
+  fn resolve_secrets() {
+    let count = env::temp_dir().join(format!("unfurl-secret-test-{}", std::process::id()));
+    let conf = load_data(format!(r#"
+services:
+  github.com:
+    auth:
+      header: "Bearer ${{HOME}}"
+  example.com:
+    auth:
+      header:
+        command: "echo run >> {}; printf 'Token abc\n'"
+  example.org:
+    auth:
+      header:
+        command: "exit 3"
+"#, count.display()).as_bytes()).unwrap();
+    let header = |domain: &str| match conf.service(domain).unwrap().auth.clone() {
+      Some(Authn::Header(Header::Authorization(secret))) => secret,
+      auth                                               => panic!("Unexpected auth: {:?}", auth),
+    };
+    assert_eq!(format!("Bearer {}", env::var("HOME").unwrap()), header("github.com").resolve().unwrap());
+
+    let secret = header("example.com");
+    assert_eq!(Secret::command(&format!("echo run >> {}; printf 'Token abc\n'", count.display())), secret);
+    assert_eq!("Token abc", secret.resolve().unwrap());
+    assert_eq!("Token abc", header("example.com").resolve().unwrap());
+    assert_eq!("run\n", fs::read_to_string(&count).unwrap());
+    let _ = fs::remove_file(&count);
+    assert!(!format!("{:?}", conf).contains("Token abc"));
+
+    assert!(header("example.org").resolve().is_err());
+
+    let start = time::Instant::now();
+    assert_eq!(Err("Secret command timed out after 0.1s".to_string()), run("sleep 5", time::Duration::from_millis(100)));
+    assert!(start.elapsed() < time::Duration::from_secs(5));
+  }
+
+  #[test]
+  fn authenticate_requests() {
+    let svc = Service{auth: Some(Authn::Header(Header::Authorization(Secret::new("Bearer abc")))), ..Service::new()};
+    let req = svc.authenticate(reqwest::Client::new().get("https://example.com/")).unwrap().build().unwrap();
+    assert_eq!("Bearer abc", req.headers()["Authorization"]);
+    assert!(!format!("{:?}", req).contains("abc"));
+    let svc = Service{auth: Some(Authn::Bearer(Secret::new("${UNFURL_TEST_UNSET}"))), ..Service::new()};
+    assert!(svc.authenticate(reqwest::Client::new().get("https://example.com/")).is_err());
+  }
+
+  #[test]
+  fn auth_schemes() {
+    let conf = load_data(r#"
+services:
+  bearer.com:
+    auth:
+      bearer: abc
+  basic.com:
+    auth:
+      basic:
+        username: someone@example.com
+        password:
+          command: "printf abc"
+  header.com:
+    auth:
+      header:
+        name: PRIVATE-TOKEN
+        value: abc
+  authorization.com:
+    auth:
+      header: Token abc
+  query.com:
+    auth:
+      query:
+        param: token
+        value: abc
+"#.as_bytes()).unwrap();
+    let request = |domain: &str| {
+      let req = reqwest::Client::new().get(format!("https://{}/a?b=c", domain));
+      conf.service(domain).unwrap().authenticate(req).unwrap().build().unwrap()
     };
-    req.header("Authorization", header)
+
+    let req = request("bearer.com");
+    assert_eq!("Bearer abc", req.headers()["Authorization"]);
+    assert!(req.headers()["Authorization"].is_sensitive());
+    let req = request("basic.com");
+    assert_eq!("Basic c29tZW9uZUBleGFtcGxlLmNvbTphYmM=", req.headers()["Authorization"]);
+    assert!(req.headers()["Authorization"].is_sensitive());
+    let req = request("header.com");
+    assert_eq!("abc", req.headers()["PRIVATE-TOKEN"]);
+    assert!(req.headers().get("Authorization").is_none());
+    let req = request("authorization.com");
+    assert_eq!("Token abc", req.headers()["Authorization"]);
+    let req = request("query.com");
+    assert_eq!("https://query.com/a?b=c&token=abc", req.url().as_str());
+
+    for (domain, expect) in [
+      ("bearer.com", "bearer"),
+      ("basic.com", "basic someone@example.com"),
+      ("header.com", "header private-token"),
+      ("authorization.com", "header authorization"),
+      ("query.com", "query token"),
+    ] {
+      assert_eq!(Some(expect.to_string()), conf.service(domain).unwrap().credentials().unwrap(), "Domain: {}", domain);
+    }
+    assert_eq!(None, Service::new().credentials().unwrap());
+
+    let svc = Service{auth: Some(Authn::Header(Header::Named{name: "Not a header".to_string(), value: Secret::new("abc")})), ..Service::new()};
+    assert!(svc.authenticate(reqwest::Client::new().get("https://example.com/")).is_err());
   }
-}
 
+  #[tokio::test]
+  async fn oauth2_client_credentials() {
+    let (url, rx) = serve_tokens(vec![(200, r#"{"access_token":"t1","token_type":"Bearer","expires_in":3600}"#)]);
+    let conf = load_data(format!(r#"
+services:
+  example.com:
+    auth:
+      oauth2:
+        token_url: {}
+        client_id: abc
+        client_secret: xyz
+        scope: read
+"#, url).as_bytes()).unwrap();
+    let svc = conf.service("example.com").unwrap();
+    let req = svc.authenticate(reqwest::Client::new().get("https://example.com/")).unwrap().build().unwrap();
+    assert!(req.headers().get("Authorization").is_none());
+    for _ in 0..2 {
+      let auth = svc.oauth2().unwrap().authorization().await.unwrap();
+      assert_eq!("Bearer t1", auth);
+      assert!(auth.is_sensitive());
+    }
+    let body = rx.recv().unwrap().body;
+    assert!(body.contains("grant_type=client_credentials"));
+    assert!(body.contains("client_id=abc"));
+    assert!(body.contains("client_secret=xyz"));
+    assert!(body.contains("scope=read"));
+    assert!(rx.try_recv().is_err());
+    assert!(!format!("{:?}", conf).contains("t1"));
+  }
+
+  #[tokio::test]
+  async fn oauth2_refresh_tokens() {
+    let (url, rx) = serve_tokens(vec![
+      (200, r#"{"access_token":"t1","expires_in":0,"refresh_token":"r2"}"#),
+      (200, r#"{"access_token":"t2","expires_in":3600}"#),
+      (401, r#"{"error":"invalid_grant"}"#),
+    ]);
+    let mut oauth = OAuth2::new(&url, Secret::new("abc"), Secret::new("xyz"));
+    oauth.refresh_token = Some(Secret::new("r1"));
+    assert_eq!("t1", oauth.access_token().await.unwrap());
+    assert!(rx.recv().unwrap().body.contains("grant_type=refresh_token&refresh_token=r1"));
+    // the first token has expired, so it's refreshed with the new refresh token
+    assert_eq!("t2", oauth.access_token().await.unwrap());
+    assert!(rx.recv().unwrap().body.contains("refresh_token=r2"));
+    assert_eq!("t2", oauth.access_token().await.unwrap());
+
+    let oauth = OAuth2::new(&url, Secret::new("abc"), Secret::new("xyz"));
+    assert!(oauth.access_token().await.is_err());
+  }
+
+  #[test]
+  fn find_services() {
+    assert_eq!(vec!["a.treno.atlassian.net", "*.treno.atlassian.net", "*.atlassian.net", "atlassian.net"], host_names("a.treno.atlassian.net"));
+    assert_eq!(vec!["atlassian.net"], host_names("atlassian.net"));
+    assert_eq!(vec!["www.bbc.co.uk", "*.bbc.co.uk", "bbc.co.uk"], host_names("www.bbc.co.uk"));
+    assert_eq!(vec!["127.0.0.1"], host_names("127.0.0.1"));
+
+    let conf = load_data(r#"
+services:
+  atlassian.net:
+    fetch:
+      retries: 1
+  "*.atlassian.net":
+    fetch:
+      retries: 2
+  treno.atlassian.net:
+    fetch:
+      retries: 3
+"#.as_bytes()).unwrap();
+    assert_eq!(Some(3), conf.fetch_for("treno.atlassian.net").retries);
+    assert_eq!(Some(2), conf.fetch_for("other.atlassian.net").retries);
+    assert_eq!(Some(1), conf.fetch_for("atlassian.net").retries);
+    assert_eq!(None, conf.fetch_for("example.com").retries);
+    let names: Vec<&str> = conf.services_for("treno.atlassian.net").into_iter().map(|(name, _)| name).collect();
+    assert_eq!(vec!["treno.atlassian.net", "*.atlassian.net", "atlassian.net"], names);
+  }
+
+}
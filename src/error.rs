@@ -4,6 +4,8 @@ use std::str;
 use std::string;
 use std::sync::mpsc;
 
+use crate::template;
+
 #[derive(Debug)]
 pub enum Error {
   IOError(io::Error),
@@ -14,7 +16,8 @@ pub enum Error {
   JsonParseError(serde_json::Error),
   ClientError(reqwest::Error),
   RecvError(mpsc::RecvError),
-  TemplateError(tinytemplate::error::Error),
+  TemplateError(template::Error),
+  ExtractError(jmespath::JmespathError),
   Invalid(String),
   AddrError,
   SendError,
@@ -52,7 +55,11 @@ impl From<serde_json::Error> for Error {
 }
 
 impl From<reqwest::Error> for Error {
-  fn from(err: reqwest::Error) -> Self {
+  fn from(mut err: reqwest::Error) -> Self {
+    // the query may carry credentials, so it's never reported
+    if let Some(url) = err.url_mut() {
+      url.set_query(None);
+    }
     Self::ClientError(err)
   }
 }
@@ -63,12 +70,24 @@ impl From<mpsc::RecvError> for Error {
   }
 }
 
-impl From<tinytemplate::error::Error> for Error {
-  fn from(err: tinytemplate::error::Error) -> Self {
+impl From<tokio::sync::oneshot::error::RecvError> for Error {
+  fn from(_: tokio::sync::oneshot::error::RecvError) -> Self {
+    Self::RecvError(mpsc::RecvError)
+  }
+}
+
+impl From<template::Error> for Error {
+  fn from(err: template::Error) -> Self {
     Self::TemplateError(err)
   }
 }
 
+impl From<jmespath::JmespathError> for Error {
+  fn from(err: jmespath::JmespathError) -> Self {
+    Self::ExtractError(err)
+  }
+}
+
 impl From<addr::error::Error<'_>> for Error {
   fn from(_: addr::error::Error<'_>) -> Self {
     Self::AddrError
@@ -93,6 +112,7 @@ impl fmt::Display for Error {
       Self::ClientError(err) => err.fmt(f),
       Self::RecvError(err) => err.fmt(f),
       Self::TemplateError(err) => err.fmt(f),
+      Self::ExtractError(err) => err.fmt(f),
       Self::Invalid(msg) => write!(f, "{}", msg),
       Self::AddrError => write!(f, "Address error"),
       Self::SendError => write!(f, "Send error"),
@@ -1,119 +1,1066 @@
 use std::thread;
-use std::sync::mpsc;
+use std::time;
+use std::sync::{mpsc, Arc, Mutex};
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::collections::hash_map::RandomState;
 
 use bytes::Bytes;
-use futures::{stream, StreamExt};
-use once_cell::sync::OnceCell;
+use futures::future;
 use reqwest;
+use tokio::sync::{oneshot, Semaphore};
 
 use crate::error;
+use crate::cache;
+use crate::config;
+use crate::graphql;
+use crate::parse;
 
 const CONCURRENT_REQUESTS: usize = 5;
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const TIMEOUT: time::Duration = time::Duration::from_secs(30);
+const RETRIES: u32 = 2;
+const BACKOFF: time::Duration = time::Duration::from_millis(500);
+const MAX_BACKOFF: time::Duration = time::Duration::from_secs(30);
+const MAX_WAIT: time::Duration = time::Duration::from_secs(300);
+const RATE_LIMIT_WAIT: time::Duration = time::Duration::from_secs(60);
+const RATE_LIMIT_RETRIES: u32 = 5;
+const GRAPHQL_BATCH_SIZE: usize = 20;
 
-static SERVICE: OnceCell<Service> = OnceCell::new();
+/// Settings which control how requests are made: how many are made at once,
+/// how long they may take, and how they are retried. The concurrency of the
+/// requests made to a single host is limited by the settings for that host,
+/// and of all requests by the global settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+  pub concurrency: usize,
+  pub connect_timeout: time::Duration,
+  pub timeout: time::Duration,
+  pub retries: u32,
+  pub backoff: time::Duration,
+  pub max_wait: time::Duration,
+}
+
+impl Default for Settings {
+  fn default() -> Self {
+    Self{
+      concurrency: CONCURRENT_REQUESTS,
+      connect_timeout: CONNECT_TIMEOUT,
+      timeout: TIMEOUT,
+      retries: RETRIES,
+      backoff: BACKOFF,
+      max_wait: MAX_WAIT,
+    }
+  }
+}
+
+impl From<&config::Fetch> for Settings {
+  fn from(conf: &config::Fetch) -> Self {
+    let secs = |v: Option<f64>, d: time::Duration| v.and_then(|v| time::Duration::try_from_secs_f64(v).ok()).unwrap_or(d);
+    Self{
+      concurrency: conf.concurrency.unwrap_or(CONCURRENT_REQUESTS).max(1),
+      connect_timeout: secs(conf.connect_timeout, CONNECT_TIMEOUT),
+      timeout: secs(conf.timeout, TIMEOUT),
+      retries: conf.retries.unwrap_or(RETRIES),
+      backoff: secs(conf.backoff, BACKOFF),
+      max_wait: secs(conf.max_wait, MAX_WAIT),
+    }
+  }
+}
+
+impl Settings {
+  /// Create a client which connects according to these settings.
+  pub fn client(&self) -> reqwest::Client {
+    match reqwest::Client::builder().connect_timeout(self.connect_timeout).build() {
+      Ok(client) => client,
+      Err(_)     => reqwest::Client::new(),
+    }
+  }
+
+  // The delay before a retry, which doubles with each attempt and is
+  // jittered so that retries of concurrent requests are spread out.
+  fn delay(&self, attempt: u32) -> time::Duration {
+    let delay = self.backoff.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_BACKOFF);
+    let jitter = (RandomState::new().build_hasher().finish() % 1000) as f64 / 1000.0;
+    delay.mul_f64(0.5 + jitter / 2.0)
+  }
+}
 
 #[derive(Debug)]
 pub struct Request {
-  key: String,
+  key: parse::Key,
+  id: usize,
   req: reqwest::RequestBuilder,
+  graphql: Option<graphql::Query>,
+  settings: Settings,
+  ttl: time::Duration,
+  cache_key: Option<String>,
+  revalidate: bool,
+  auth: Option<String>,
+  credentials: Option<String>,
+  oauth2: Option<config::OAuth2>,
+  parts: Vec<(String, Request)>,
 }
 
 impl Request{
-  pub fn new(key: &str, req: reqwest::RequestBuilder) -> Self {
+  pub fn new(key: parse::Key, req: reqwest::RequestBuilder) -> Self {
+    Request{
+      key,
+      id: 0,
+      req,
+      graphql: None,
+      settings: Settings::default(),
+      ttl: time::Duration::ZERO,
+      cache_key: None,
+      revalidate: false,
+      auth: None,
+      credentials: None,
+      oauth2: None,
+      parts: Vec::new(),
+    }
+  }
+
+  /// Note where the credentials used for this request were found.
+  pub fn with_auth(self, auth: &str) -> Self {
+    Request{
+      auth: Some(auth.to_string()),
+      ..self
+    }
+  }
+
+  /// Identify the credentials used for this request, without including any
+  /// secrets, so that its response is cached separately from responses to
+  /// requests made with other credentials.
+  pub fn with_credentials(self, credentials: &str) -> Self {
+    Request{
+      credentials: Some(credentials.to_string()),
+      ..self
+    }
+  }
+
+  /// Authenticate this request with an OAuth2 access token, which is added
+  /// when the request is made, requesting one first if need be.
+  pub fn with_oauth2(self, oauth2: config::OAuth2) -> Self {
+    Request{
+      oauth2: Some(oauth2),
+      ..self
+    }
+  }
+
+  /// Where the credentials used for this request were found, if it has any.
+  pub fn auth(&self) -> Option<&str> {
+    self.auth.as_deref()
+  }
+
+  /// Set how long the response to this request may be used from the cache
+  /// before the service must be asked whether it has changed.
+  pub fn with_ttl(self, ttl: time::Duration) -> Self {
+    Request{
+      ttl,
+      ..self
+    }
+  }
+
+  pub fn with_settings(self, settings: Settings) -> Self {
     Request{
-      key: key.to_string(),
-      req: req,
+      settings,
+      ..self
+    }
+  }
+
+  /// Mark this as a request for a GraphQL query, which may be batched with
+  /// other queries made to the same endpoint.
+  pub fn with_graphql(self, query: graphql::Query) -> Self {
+    Request{
+      graphql: Some(query),
+      ..self
+    }
+  }
+
+  /// Add a named request which is made along with this one. Its response
+  /// is available from this request's response as a part of it.
+  pub fn with_part(mut self, name: &str, req: Request) -> Self {
+    self.parts.push((name.to_string(), req));
+    self
+  }
+
+  #[cfg(test)]
+  pub fn build(self) -> reqwest::Result<reqwest::Request> {
+    self.req.build()
+  }
+
+  #[cfg(test)]
+  pub fn part_url(&self, name: &str) -> Option<String> {
+    let (_, part) = self.parts.iter().find(|(n, _)| n == name)?;
+    Some(part.req.try_clone()?.build().ok()?.url().to_string())
+  }
+
+  // A hash identifying the request that is made, which is the same for
+  // identical requests made for different links.
+  fn identity(&self) -> Option<String> {
+    cache::key(&self.req.try_clone()?.build().ok()?, self.credentials.as_deref())
+  }
+
+  // Requests which can be batched together have the same batch key.
+  fn batch_key(&self) -> Option<String> {
+    if !self.graphql.as_ref()?.can_batch() || self.revalidate {
+      return None;
+    }
+    let req = self.req.try_clone()?.build().ok()?;
+    Some(format!("{} {:?} {:?}", req.url(), req.headers(), self.credentials))
+  }
+}
+
+// One request, or a batch of GraphQL requests made as one request.
+enum Job {
+  Single(Box<Request>),
+  Batch(Vec<Request>, Box<reqwest::RequestBuilder>),
+}
+
+impl Job {
+  fn single(req: Request) -> Job {
+    Job::Single(Box::new(req))
+  }
+
+  fn settings(&self) -> &Settings {
+    match self {
+      Job::Single(req)     => &req.settings,
+      Job::Batch(reqs, _)  => &reqs[0].settings,
+    }
+  }
+
+  fn oauth2(&self) -> Option<&config::OAuth2> {
+    match self {
+      Job::Single(req)    => req.oauth2.as_ref(),
+      Job::Batch(reqs, _) => reqs[0].oauth2.as_ref(),
+    }
+  }
+
+  fn host(&self) -> Option<String> {
+    let req = match self {
+      Job::Single(req)   => &req.req,
+      Job::Batch(_, req) => req,
+    };
+    req.try_clone()?.build().ok()?.url().host_str().map(|h| h.to_string())
+  }
+
+  // Only requests which are safe to repeat are retried; GraphQL requests are
+  // always queries, which are.
+  fn can_retry(&self) -> bool {
+    match self {
+      Job::Single(req) => req.graphql.is_some() || req.req.try_clone()
+        .and_then(|r| r.build().ok())
+        .is_some_and(|r| r.method().is_idempotent()),
+      Job::Batch(..)   => true,
+    }
+  }
+}
+
+// The outcome of a request.
+#[derive(Debug)]
+struct Fetched {
+  content_type: Option<String>,
+  etag: Option<String>,
+  last_modified: Option<String>,
+  not_modified: bool,
+  data: Result<Bytes, error::Error>,
+}
+
+impl Fetched {
+  fn error(err: error::Error) -> Self {
+    Fetched{
+      content_type: None,
+      etag: None,
+      last_modified: None,
+      not_modified: false,
+      data: Err(err),
     }
   }
 }
 
 #[derive(Debug)]
 struct Requests {
-  tx: mpsc::Sender<Vec<Response>>,
+  tx: oneshot::Sender<Vec<Response>>,
   reqs: Vec<Request>,
 }
 
 #[derive(Debug)]
 pub struct Response {
-  key: String,
+  key: parse::Key,
+  content_type: Option<String>,
   data: Result<Bytes, error::Error>,
+  parts: Vec<(String, Response)>,
 }
 
 impl Response {
-  pub fn key<'a>(&'a self) -> &'a str {
+  pub fn key(&self) -> &parse::Key {
     &self.key
   }
 
+  pub fn content_type(&self) -> Option<&str> {
+    self.content_type.as_deref()
+  }
+
   pub fn data<'a>(&'a self) -> &'a Result<Bytes, error::Error> {
     &self.data
   }
+
+  pub fn part(&self, name: &str) -> Option<&Response> {
+    self.parts.iter().find(|(n, _)| n == name).map(|(_, rsp)| rsp)
+  }
+
+  // A copy of the response, without its parts, for an identical request.
+  fn copy(&self, key: parse::Key) -> Response {
+    Response{
+      key,
+      content_type: self.content_type.clone(),
+      data: match &self.data {
+        Ok(data) => Ok(data.clone()),
+        Err(err) => Err(error::Error::Invalid(err.to_string())),
+      },
+      parts: Vec::new(),
+    }
+  }
+
+  #[cfg(test)]
+  pub fn with_data(key: parse::Key, content_type: Option<&str>, data: &'static str) -> Self {
+    Response{
+      key,
+      content_type: content_type.map(|c| c.to_string()),
+      data: Ok(Bytes::from(data)),
+      parts: Vec::new(),
+    }
+  }
+
+  #[cfg(test)]
+  pub fn with_part(mut self, name: &str, rsp: Response) -> Self {
+    self.parts.push((name.to_string(), rsp));
+    self
+  }
 }
 
 pub struct Service {
   tx: mpsc::Sender<Requests>,
 }
 
-impl Service {
-  pub fn instance() -> &'static Service {
-    SERVICE.get_or_init(|| { Self::new() })
+// A host's limit on the rate of requests, as reported by its response.
+#[derive(Debug, PartialEq)]
+enum Limit {
+  /// The request was rejected; it may be retried after the duration
+  Exceeded(time::Duration),
+  /// The request succeeded, but no more may be made for the duration
+  Exhausted(time::Duration),
+}
+
+// When each host which has limited the rate of our requests will accept
+// requests again. This is shared by every request the service makes. A note
+// is written each time we start waiting on a host unless notes are turned
+// off, and it includes how long we're waiting when debugging.
+#[derive(Debug, Clone, Default)]
+struct Limits {
+  until: Arc<Mutex<HashMap<String, time::Instant>>>,
+  notify: bool,
+  debug: bool,
+}
+
+impl Limits {
+  fn new(notify: bool, debug: bool) -> Self {
+    Self{
+      until: Arc::new(Mutex::new(HashMap::new())),
+      notify,
+      debug,
+    }
+  }
+
+  // Wait until the host will accept requests.
+  async fn wait(&self, host: &str) {
+    loop {
+      let until = match self.until.lock() {
+        Ok(limits) => limits.get(host).copied(),
+        Err(_)     => None,
+      };
+      match until {
+        Some(until) if until > time::Instant::now() => tokio::time::sleep_until(until.into()).await,
+        _                                           => return,
+      }
+    }
   }
 
-  fn new() -> Service {
+  // Stop making requests to the host for a while, noting when we start to
+  // wait longer than we already were.
+  fn pause(&self, host: &str, wait: time::Duration) {
+    let until = time::Instant::now() + wait;
+    let mut limits = match self.until.lock() {
+      Ok(limits) => limits,
+      Err(_)     => return,
+    };
+    if limits.get(host).is_none_or(|prev| until > *prev + time::Duration::from_secs(1)) {
+      if self.debug {
+        eprintln!("* * * {} is limiting the rate of requests; waiting {}s", host, wait.as_secs_f64().ceil());
+      } else if self.notify {
+        eprintln!("{} is limiting the rate of requests; waiting", host);
+      }
+      limits.insert(host.to_string(), until);
+    }
+  }
+}
+
+// The state shared by every batch of requests the service makes, so that
+// batches are fetched at once while keeping to the limits on how many
+// requests are made at a time, both in all and to each host.
+struct Shared {
+  slots: Semaphore,
+  hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+  limits: Limits,
+  cache: Option<cache::Cache>,
+}
+
+impl Shared {
+  fn new(n: usize, limits: Limits, cache: Option<cache::Cache>) -> Self {
+    Self{
+      slots: Semaphore::new(n),
+      hosts: Mutex::new(HashMap::new()),
+      limits,
+      cache,
+    }
+  }
+
+  // The permits for requests to a host, which allow up to limit at a time.
+  fn host(&self, host: &str, limit: usize) -> Arc<Semaphore> {
+    match self.hosts.lock() {
+      Ok(mut hosts) => hosts.entry(host.to_string()).or_insert_with(|| Arc::new(Semaphore::new(limit))).clone(),
+      Err(_)        => Arc::new(Semaphore::new(limit)),
+    }
+  }
+}
+
+impl Service {
+  /// Start a service which makes requests with the settings, using the
+  /// cache if there is one; without a cache, every request is made. Waits
+  /// for hosts which limit the rate of requests are noted on standard error
+  /// when notifying, and in more detail along with responses which can't be
+  /// cached when debugging. The
+  /// service runs until it is dropped. Each batch of requests is fetched as
+  /// soon as it's received, alongside any earlier batches.
+  pub fn new(settings: Settings, cache: Option<cache::Cache>, notify: bool, debug: bool) -> Service {
     let (q_tx, q_rx) = mpsc::channel();
     let svc = Service{tx: q_tx};
-    thread::spawn(|| { Service::run(q_rx) });
+    thread::spawn(move || { Service::run(settings, cache, Limits::new(notify, debug), q_rx) });
     svc
   }
 
-  pub fn fetch_requests(&self, reqs: Vec<Request>) -> Result<mpsc::Receiver<Vec<Response>>, error::Error> {
-    let (p_tx, p_rx) = mpsc::channel();
-    match self.tx.send(Requests{tx: p_tx, reqs: reqs}) {
+  /// Make requests, producing a receiver for their responses which can be
+  /// waited on either by blocking or from async code.
+  pub fn fetch_requests(&self, reqs: Vec<Request>) -> Result<oneshot::Receiver<Vec<Response>>, error::Error> {
+    let (p_tx, p_rx) = oneshot::channel();
+    match self.tx.send(Requests{tx: p_tx, reqs}) {
       Ok(_)  => Ok(p_rx),
       Err(_) => Err(error::Error::SendError),
     }
   }
 
-  fn run(rx: mpsc::Receiver<Requests>) {
+  fn run(settings: Settings, cache: Option<cache::Cache>, limits: Limits, rx: mpsc::Receiver<Requests>) {
+    let shared = Arc::new(Shared::new(settings.concurrency, limits, cache));
     tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap().block_on(async {
+      let mut tasks = Vec::new();
       loop {
         let x = match rx.recv() {
-          Ok(x)    => x,
-          Err(err) => {
-            println!("*** Could not receive: {}", err);
-            return;
-          },
+          Ok(x)  => x,
+          Err(_) => break, // the service has been dropped
         };
-        let rsps = fetch_n(CONCURRENT_REQUESTS, x.reqs).await;
-        if let Err(err) = x.tx.send(rsps) {
-          println!("*** Could not send: {}", err);
-          return;
-        }
+        let shared = shared.clone();
+        tasks.retain(|task: &tokio::task::JoinHandle<()>| !task.is_finished());
+        tasks.push(tokio::spawn(async move {
+          let rsps = fetch_n(&shared, x.reqs).await;
+          // if nobody is waiting for the responses any longer, they're dropped
+          let _ = x.tx.send(rsps);
+        }));
       }
-    }) 
+      // batches which were already received are still answered
+      future::join_all(tasks).await;
+    })
+  }
+}
+
+// Fetch requests and their parts, all of which are made independently and
+// within the shared limits; the responses to parts are then reassembled.
+async fn fetch_n(shared: &Shared, reqs: Vec<Request>) -> Vec<Response> {
+  let mut flat = Vec::new();
+  let mut owners = Vec::new();
+  for req in reqs {
+    flatten(req, None, &mut flat, &mut owners);
+  }
+  let (flat, dups) = dedup(flat);
+  let (flat, mut rsps) = match &shared.cache {
+    Some(cache) => lookup(cache, flat, time::SystemTime::now()),
+    None        => (flat, Vec::new()),
+  };
+  rsps.extend(fetch_each(shared, flat).await);
+  for (key, id, orig) in dups {
+    if let Some((_, rsp)) = rsps.iter().find(|(id, _)| *id == orig) {
+      let copy = rsp.copy(key);
+      rsps.push((id, copy));
+    }
+  }
+  assemble(owners, rsps)
+}
+
+// Remove requests which are identical to an earlier request, noting the id
+// of the earlier request for each of them so its response can be copied.
+fn dedup(reqs: Vec<Request>) -> (Vec<Request>, Vec<(parse::Key, usize, usize)>) {
+  let mut seen: HashMap<String, usize> = HashMap::new();
+  let mut unique = Vec::new();
+  let mut dups = Vec::new();
+  for req in reqs {
+    match req.identity() {
+      Some(identity) => match seen.get(&identity) {
+        Some(orig) => dups.push((req.key, req.id, *orig)),
+        None       => {
+          seen.insert(identity, req.id);
+          unique.push(req);
+        },
+      },
+      None => unique.push(req),
+    }
+  }
+  (unique, dups)
+}
+
+// Look requests up in the cache. Requests with fresh entries are answered
+// from the cache; those with stale entries that the service can tell us are
+// unchanged are made conditional, and the rest are made as usual.
+fn lookup(cache: &cache::Cache, reqs: Vec<Request>, now: time::SystemTime) -> (Vec<Request>, Vec<(usize, Response)>) {
+  let mut pending = Vec::new();
+  let mut answered = Vec::new();
+  for mut req in reqs {
+    let key = match req.identity() {
+      Some(key) => key,
+      None      => {
+        pending.push(req);
+        continue;
+      },
+    };
+    match cache.get(&key) {
+      Some(entry) if entry.is_fresh(now) => {
+        answered.push((req.id, Response{key: req.key, content_type: entry.content_type, data: Ok(entry.data), parts: Vec::new()}));
+      },
+      Some(entry) if entry.can_revalidate() => {
+        if let Some(etag) = &entry.etag {
+          req.req = req.req.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+        if let Some(modified) = &entry.last_modified {
+          req.req = req.req.header(reqwest::header::IF_MODIFIED_SINCE, modified);
+        }
+        req.cache_key = Some(key);
+        req.revalidate = true;
+        pending.push(req);
+      },
+      _ => {
+        req.cache_key = Some(key);
+        pending.push(req);
+      },
+    }
+  }
+  (pending, answered)
+}
+
+// Produce the response to a request, using the entry being revalidated if
+// the service tells us it hasn't changed, and store it if it can be cached.
+fn respond(key: parse::Key, ttl: time::Duration, path: Option<String>, fetched: Fetched, cache: Option<&cache::Cache>, debug: bool) -> Response {
+  let (cache, path) = match (cache, path) {
+    (Some(cache), Some(path)) => (cache, path),
+    _                         => return Response{key, content_type: fetched.content_type, data: fetched.data, parts: Vec::new()},
+  };
+  let entry = match fetched.data {
+    Ok(_) if fetched.not_modified => match cache.get(&path) {
+      Some(stale) => stale.renew(),
+      None        => return Response{key, content_type: None, data: Err(error::Error::Invalid("Cached response is missing".to_string())), parts: Vec::new()},
+    },
+    Ok(data) if !ttl.is_zero() || fetched.etag.is_some() || fetched.last_modified.is_some() => {
+      cache::Entry::new(ttl, fetched.etag, fetched.last_modified, fetched.content_type, data)
+    },
+    data => return Response{key, content_type: fetched.content_type, data, parts: Vec::new()},
+  };
+  match cache.put(&path, &entry) {
+    Err(err) if debug => eprintln!("* * * Could not cache response: {}", err),
+    _                 => {},
+  }
+  Response{key, content_type: entry.content_type, data: Ok(entry.data), parts: Vec::new()}
+}
+
+// Reassemble responses to flattened requests, attaching the responses to
+// parts to the responses to their owners.
+fn assemble(owners: Vec<Option<(usize, String)>>, flat: Vec<(usize, Response)>) -> Vec<Response> {
+  let mut rsps: Vec<Option<Response>> = owners.iter().map(|_| None).collect();
+  for (id, rsp) in flat {
+    rsps[id] = Some(rsp);
+  }
+  // parts always follow their owners, so they're attached first
+  let mut res = Vec::new();
+  for (id, owner) in owners.into_iter().enumerate().rev() {
+    let rsp = match rsps[id].take() {
+      Some(rsp) => rsp,
+      None      => continue,
+    };
+    match owner {
+      Some((parent, name)) => if let Some(parent) = rsps[parent].as_mut() {
+        parent.parts.insert(0, (name, rsp));
+      },
+      None => res.push(rsp),
+    }
+  }
+  res.reverse();
+  res
+}
+
+// Give each request and its parts, in order, an id which is its index.
+fn flatten(mut req: Request, owner: Option<(usize, String)>, flat: &mut Vec<Request>, owners: &mut Vec<Option<(usize, String)>>) {
+  let id = owners.len();
+  let parts = std::mem::take(&mut req.parts);
+  req.id = id;
+  owners.push(owner);
+  flat.push(req);
+  for (name, part) in parts {
+    flatten(part, Some((id, name)), flat, owners);
   }
 }
 
-async fn fetch_n(n: usize, reqs: Vec<Request>) -> Vec<Response> {
-  stream::iter(reqs)
-    .map(|req| {
+// Every job is started at once, but only as many requests as there are
+// shared slots are made at a time. Jobs wait for their host's permit and for
+// any limit on its rate before taking a slot, so that hosts which are paused
+// don't hold up others.
+async fn fetch_each(shared: &Shared, reqs: Vec<Request>) -> Vec<(usize, Response)> {
+  let (slots, limits, cache) = (&shared.slots, &shared.limits, shared.cache.as_ref());
+  let jobs: Vec<(Job, String, Arc<Semaphore>)> = batch(reqs).into_iter().map(|job| {
+    let host = job.host().unwrap_or_default();
+    let sem = shared.host(&host, job.settings().concurrency);
+    (job, host, sem)
+  }).collect();
+  let rsps: Vec<Vec<(usize, Response)>> = future::join_all(jobs.into_iter()
+    .map(|(job, host, sem)| {
       async move {
-        Response{
-          key: req.key.clone(),
-          data: match req.req.send().await {
-            Err(err) => Err(err.into()),
-            Ok(rsp)  => match rsp.error_for_status() {
-              Ok(rsp) => match rsp.bytes().await {
-                Ok(data) => Ok(data),
-                Err(err) => Err(err.into()),
-              },
-              Err(err) => Err(err.into()),
-            },
-          }
+        let _permit = sem.acquire().await.ok();
+        let retry = job.can_retry();
+        let oauth2 = job.oauth2().cloned();
+        match job {
+          Job::Single(req) => {
+            let fetched = match authorize(req.req, oauth2.as_ref()).await {
+              Ok(builder) => fetch(builder, &host, limits, slots, &req.settings, retry).await,
+              Err(err)    => Fetched::error(err),
+            };
+            vec![(req.id, respond(req.key, req.ttl, req.cache_key, fetched, cache, limits.debug))]
+          },
+          Job::Batch(reqs, req) => {
+            let fetched = match authorize(*req, oauth2.as_ref()).await {
+              Ok(builder) => fetch(builder, &host, limits, slots, &reqs[0].settings, retry).await,
+              Err(err)    => Fetched::error(err),
+            };
+            split(reqs, fetched).into_iter().map(|(req, fetched)| (req.id, respond(req.key, req.ttl, req.cache_key, fetched, cache, limits.debug))).collect()
+          },
         }
       }
-    })
-    .buffer_unordered(n)
-    .collect()
-    .await
+    })).await;
+  rsps.into_iter().flatten().collect()
+}
+
+// Add an OAuth2 access token to a request which needs one.
+async fn authorize(req: reqwest::RequestBuilder, oauth2: Option<&config::OAuth2>) -> Result<reqwest::RequestBuilder, error::Error> {
+  match oauth2 {
+    Some(oauth2) => Ok(req.header(reqwest::header::AUTHORIZATION, oauth2.authorization().await?)),
+    None         => Ok(req),
+  }
+}
+
+// Make a request, retrying it if it's allowed to be retried and it fails in
+// a way that might not be permanent. Requests which are rejected because
+// the host is limiting the rate of requests are always retried once the
+// limit resets, unless that would take longer than we're willing to wait.
+// A slot is only held while the request is being made.
+async fn fetch(mut req: reqwest::RequestBuilder, host: &str, limits: &Limits, slots: &Semaphore, settings: &Settings, retry: bool) -> Fetched {
+  let mut attempt = 0;
+  let mut limited = 0;
+  loop {
+    limits.wait(host).await;
+    let next = req.try_clone();
+    let (fetched, limit) = {
+      let _slot = slots.acquire().await.ok();
+      fetch_once(req.timeout(settings.timeout)).await
+    };
+    match limit {
+      Some(Limit::Exceeded(wait)) if wait <= settings.max_wait && limited < RATE_LIMIT_RETRIES && next.is_some() => {
+        limits.pause(host, wait);
+        req = next.unwrap();
+        limited += 1;
+        continue;
+      },
+      Some(Limit::Exhausted(wait)) if wait <= settings.max_wait => limits.pause(host, wait),
+      _ => {},
+    }
+    match (next, &fetched.data) {
+      (Some(next), Err(error::Error::ClientError(err))) if retry && attempt < settings.retries && is_transient(err) => {
+        tokio::time::sleep(settings.delay(attempt)).await;
+        req = next;
+        attempt += 1;
+      },
+      _ => return fetched,
+    }
+  }
+}
+
+// Determine whether a response tells us that the host is limiting the rate
+// of our requests, and if so, for how long.
+fn rate_limit(status: reqwest::StatusCode, headers: &reqwest::header::HeaderMap, now: time::SystemTime) -> Option<Limit> {
+  let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.trim());
+  let retry_after = header("Retry-After").and_then(|v| match v.parse::<u64>() {
+    Ok(secs) => Some(time::Duration::from_secs(secs)),
+    Err(_)   => until(chrono::DateTime::parse_from_rfc2822(v).ok()?.timestamp(), now),
+  });
+  let reset = header("X-RateLimit-Reset").and_then(|v| match v.parse::<i64>() {
+    Ok(secs) => until(secs, now),
+    Err(_)   => until(chrono::DateTime::parse_from_rfc3339(v).ok()?.timestamp(), now),
+  });
+  let exhausted = header("X-RateLimit-Remaining").and_then(|v| v.parse::<u64>().ok()) == Some(0);
+  let wait = retry_after.or(reset);
+  if status == reqwest::StatusCode::TOO_MANY_REQUESTS || (status == reqwest::StatusCode::FORBIDDEN && (retry_after.is_some() || exhausted)) {
+    Some(Limit::Exceeded(wait.unwrap_or(RATE_LIMIT_WAIT)))
+  } else if status.is_success() && exhausted {
+    wait.map(Limit::Exhausted)
+  } else {
+    None
+  }
+}
+
+// The time from now until a Unix timestamp, which is nothing if it's past.
+fn until(timestamp: i64, now: time::SystemTime) -> Option<time::Duration> {
+  let then = time::UNIX_EPOCH + time::Duration::from_secs(timestamp.try_into().ok()?);
+  Some(then.duration_since(now).unwrap_or_default())
+}
+
+fn is_transient(err: &reqwest::Error) -> bool {
+  err.is_connect() || err.is_timeout() || err.is_body() || err.status().is_some_and(|s| s.is_server_error())
+}
+
+async fn fetch_once(req: reqwest::RequestBuilder) -> (Fetched, Option<Limit>) {
+  let rsp = match req.send().await {
+    Ok(rsp)  => rsp,
+    Err(err) => return (Fetched::error(err.into()), None),
+  };
+  let limit = rate_limit(rsp.status(), rsp.headers(), time::SystemTime::now());
+  let not_modified = rsp.status() == reqwest::StatusCode::NOT_MODIFIED;
+  match rsp.error_for_status() {
+    Ok(rsp) => {
+      let header = |name| rsp.headers().get(name).and_then(|v: &reqwest::header::HeaderValue| v.to_str().ok()).map(|v| v.to_string());
+      let content_type = header(reqwest::header::CONTENT_TYPE);
+      let etag = header(reqwest::header::ETAG);
+      let last_modified = header(reqwest::header::LAST_MODIFIED);
+      let data = rsp.bytes().await.map_err(|err| err.into());
+      (Fetched{content_type, etag, last_modified, not_modified, data}, limit)
+    },
+    Err(err) => (Fetched::error(err.into()), limit),
+  }
+}
+
+// Group GraphQL requests made to the same endpoint into batches. Requests
+// which cannot be batched are made on their own.
+fn batch(reqs: Vec<Request>) -> Vec<Job> {
+  let mut jobs = Vec::new();
+  let mut groups: Vec<(String, Vec<Request>)> = Vec::new();
+  for req in reqs {
+    match req.batch_key() {
+      Some(key) => match groups.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, group)) => group.push(req),
+        None             => groups.push((key, vec![req])),
+      },
+      None => jobs.push(Job::single(req)),
+    }
+  }
+  for (_, mut group) in groups {
+    while !group.is_empty() {
+      let mut chunk: Vec<Request> = group.drain(..group.len().min(GRAPHQL_BATCH_SIZE)).collect();
+      if chunk.len() == 1 {
+        jobs.extend(chunk.drain(..).map(Job::single));
+        continue;
+      }
+      let queries: Vec<&graphql::Query> = chunk.iter().filter_map(|r| r.graphql.as_ref()).collect();
+      match graphql::batch(&queries) {
+        Ok(query) => match chunk[0].req.try_clone() {
+          Some(req) => jobs.push(Job::Batch(chunk, Box::new(req.json(&query.body())))),
+          None      => jobs.extend(chunk.drain(..).map(Job::single)),
+        },
+        Err(_) => jobs.extend(chunk.drain(..).map(Job::single)),
+      }
+    }
+  }
+  jobs
 }
 
+// Divide the response to a batch into the responses to each of its requests.
+fn split(reqs: Vec<Request>, fetched: Fetched) -> Vec<(Request, Fetched)> {
+  let content_type = fetched.content_type;
+  let parts = match fetched.data.and_then(|data| Ok(serde_json::from_slice::<serde_json::Value>(&data)?)) {
+    Ok(rsp)  => graphql::split(&rsp, reqs.len()).iter().map(|part| Ok(Bytes::from(part.to_string()))).collect(),
+    Err(err) => reqs.iter().map(|_| Err(error::Error::Invalid(err.to_string()))).collect::<Vec<_>>(),
+  };
+  reqs.into_iter().zip(parts).map(|(req, data)| {
+    (req, Fetched{content_type: content_type.clone(), etag: None, last_modified: None, not_modified: false, data})
+  }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::testing;
+
+  // The key for a link named for a test.
+  fn key(name: &str) -> parse::Key {
+    parse::Key::parse(&format!("https://example.com/{}", name)).unwrap()
+  }
+
+  fn name(key: &parse::Key) -> &str {
+    key.url().path().trim_start_matches('/')
+  }
+
+  fn request(key: &str, url: &str, query: Option<&str>) -> Request {
+    let req = Request::new(self::key(key), reqwest::Client::new().post(url));
+    match query {
+      Some(query) => req.with_graphql(graphql::Query::new(query, &HashMap::new()).unwrap()),
+      None        => req,
+    }
+  }
+
+  #[test]
+  fn batch_requests() {
+    let jobs = batch(vec![
+      request("a", "https://example.com/graphql", Some("{ a { id } }")),
+      request("b", "https://example.com/rest", None),
+      request("c", "https://example.com/graphql", Some("query { c { id } }")),
+      request("d", "https://example.org/graphql", Some("{ d { id } }")),
+      request("e", "https://example.com/graphql", Some("{ ...E } fragment E on Query { e { id } }")),
+    ]);
+    let jobs: Vec<(Vec<parse::Key>, Option<serde_json::Value>)> = jobs.into_iter().map(|job| match job {
+      Job::Single(req)     => (vec![req.key], None),
+      Job::Batch(reqs, req) => {
+        let req = req.build().unwrap();
+        (reqs.into_iter().map(|r| r.key).collect(), serde_json::from_slice(req.body().unwrap().as_bytes().unwrap()).ok())
+      },
+    }).collect();
+    assert_eq!(vec![
+      (vec![key("b")], None),
+      (vec![key("e")], None),
+      (vec![key("a"), key("c")], Some(serde_json::json!({
+        "query": "query {\n q0_a: a { id } \n q1_c: c { id } \n}",
+        "variables": {},
+      }))),
+      (vec![key("d")], None),
+    ], jobs);
+  }
+
+  #[test]
+  fn assemble_parts() {
+    let req = request("a", "https://example.com/a", None)
+      .with_part("b", request("a", "https://example.com/b", None)
+        .with_part("c", request("a", "https://example.com/c", None)))
+      .with_part("d", request("a", "https://example.com/d", None));
+    let mut flat = Vec::new();
+    let mut owners = Vec::new();
+    flatten(req, None, &mut flat, &mut owners);
+    flatten(request("e", "https://example.com/e", None), None, &mut flat, &mut owners);
+    assert_eq!(vec![0, 1, 2, 3, 4], flat.iter().map(|r| r.id).collect::<Vec<_>>());
+
+    let rsps = assemble(owners, vec![3, 0, 4, 2, 1].into_iter().map(|id| (id, Response::with_data(flat[id].key.clone(), None, ""))).collect());
+    assert_eq!(2, rsps.len());
+    assert_eq!(("a", "e"), (name(rsps[0].key()), name(rsps[1].key())));
+    assert_eq!(vec!["b", "d"], rsps[0].parts.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>());
+    assert!(rsps[0].part("b").and_then(|b| b.part("c")).is_some());
+    assert!(rsps[0].part("c").is_none());
+  }
+
+  #[test]
+  fn split_responses() {
+    let reqs = || vec![request("a", "https://example.com/graphql", None), request("c", "https://example.com/graphql", None)];
+    let fetched = |data: &'static str| Fetched{content_type: Some("application/json".to_string()), data: Ok(Bytes::from(data)), ..Fetched::error(error::Error::NotFound)};
+    let rsps = split(reqs(), fetched(r#"{"data": {"q0_a": {"id": 1}, "q1_c": {"id": 2}}}"#));
+    assert_eq!(vec![("a", r#"{"data":{"a":{"id":1}}}"#), ("c", r#"{"data":{"c":{"id":2}}}"#)], rsps.iter()
+      .map(|(r, f)| (name(&r.key), std::str::from_utf8(f.data.as_ref().unwrap()).unwrap()))
+      .collect::<Vec<_>>());
+    let rsps = split(reqs(), fetched("Not JSON"));
+    assert!(rsps.iter().all(|(_, f)| f.data.is_err()));
+  }
+
+  // Serve each of the responses in turn, returning the URL served on and a
+  // receiver for each request.
+  fn serve(rsps: Vec<&str>) -> (String, mpsc::Receiver<testing::Received>) {
+    let (addr, rx) = testing::serve(rsps.into_iter().map(|rsp| rsp.to_string()).collect());
+    (format!("http://{}/", addr), rx)
+  }
+
+  fn settings(retries: u32) -> Settings {
+    Settings{
+      retries,
+      backoff: time::Duration::from_millis(1),
+      ..Settings::default()
+    }
+  }
+
+  const UNAVAILABLE: &str = "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+  const NOT_FOUND: &str = "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+  const OK: &str = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nConnection: close\r\n\r\nOk";
+
+  #[tokio::test]
+  async fn retry_requests() {
+    let (addr, served) = serve(vec![UNAVAILABLE, UNAVAILABLE, OK]);
+    let fetched = fetch(reqwest::Client::new().get(&addr), "localhost", &Limits::default(), &Semaphore::new(1), &settings(2), true).await;
+    assert_eq!(Some("text/plain".to_string()), fetched.content_type);
+    assert_eq!(b"Ok".as_ref(), fetched.data.unwrap().as_ref());
+    assert_eq!(3, served.try_iter().count());
+
+    let (addr, served) = serve(vec![UNAVAILABLE, UNAVAILABLE]);
+    assert!(fetch(reqwest::Client::new().get(&addr), "localhost", &Limits::default(), &Semaphore::new(1), &settings(1), true).await.data.is_err());
+    assert_eq!(2, served.try_iter().count());
+
+    let (addr, served) = serve(vec![NOT_FOUND]);
+    assert!(fetch(reqwest::Client::new().get(&addr), "localhost", &Limits::default(), &Semaphore::new(1), &settings(2), true).await.data.is_err());
+    assert_eq!(1, served.try_iter().count());
+
+    let (addr, served) = serve(vec![UNAVAILABLE]);
+    assert!(fetch(reqwest::Client::new().post(&addr), "localhost", &Limits::default(), &Semaphore::new(1), &settings(2), false).await.data.is_err());
+    assert_eq!(1, served.try_iter().count());
+  }
+
+  #[test]
+  fn retry_only_idempotent() {
+    assert!(Job::single(Request::new(key("a"), reqwest::Client::new().get("https://example.com/"))).can_retry());
+    assert!(!Job::single(request("a", "https://example.com/", None)).can_retry());
+    assert!(Job::single(request("a", "https://example.com/", Some("{ a }"))).can_retry());
+  }
+
+  #[test]
+  fn load_settings() {
+    let conf = config::Fetch{concurrency: Some(0), timeout: Some(2.5), retries: Some(0), ..config::Fetch::default()};
+    assert_eq!(Settings{
+      concurrency: 1,
+      timeout: time::Duration::from_millis(2500),
+      retries: 0,
+      ..Settings::default()
+    }, Settings::from(&conf));
+    assert_eq!(Settings::default(), Settings::from(&config::Fetch{timeout: Some(-1.0), ..config::Fetch::default()}));
+
+    let settings = Settings{backoff: time::Duration::from_secs(1), ..Settings::default()};
+    for (attempt, min, max) in [(0, 500, 1000), (1, 1000, 2000), (2, 2000, 4000), (10, 15000, 30000)] {
+      let delay = settings.delay(attempt).as_millis();
+      assert!(delay >= min && delay <= max, "Attempt {}: {}ms", attempt, delay);
+    }
+  }
+
+  #[tokio::test]
+  async fn wait_for_rate_limits() {
+    let limited = "HTTP/1.1 429 Too Many Requests\r\nRetry-After: 1\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let (addr, served) = serve(vec![limited, OK]);
+    let start = time::Instant::now();
+    let fetched = fetch(reqwest::Client::new().post(&addr), "localhost", &Limits::default(), &Semaphore::new(1), &settings(0), false).await;
+    assert_eq!(b"Ok".as_ref(), fetched.data.unwrap().as_ref());
+    assert_eq!(2, served.try_iter().count());
+    assert!(start.elapsed() >= time::Duration::from_secs(1));
+
+    let (addr, served) = serve(vec![limited]);
+    let settings = Settings{max_wait: time::Duration::from_millis(500), ..settings(0)};
+    assert!(fetch(reqwest::Client::new().get(&addr), "localhost", &Limits::default(), &Semaphore::new(1), &settings, true).await.data.is_err());
+    assert_eq!(1, served.try_iter().count());
+  }
+
+  #[tokio::test]
+  async fn paused_hosts_hold_no_slots() {
+    let (addr, served) = serve(vec![OK]);
+    let get = |name: &str, url: &str| Request::new(key(name), reqwest::Client::new().get(url));
+    let shared = Shared::new(1, Limits::default(), None);
+    shared.limits.pause("example.invalid", time::Duration::from_secs(10));
+    let fetching = fetch_each(&shared, vec![get("a", "http://example.invalid/"), get("b", &addr)]);
+    tokio::select! {
+      _ = fetching                                         => panic!("Paused request was made"),
+      _ = tokio::time::sleep(time::Duration::from_secs(1)) => {},
+    }
+    assert!(served.recv_timeout(time::Duration::from_secs(1)).is_ok());
+  }
+
+  #[tokio::test]
+  async fn fetch_identical_requests_once() {
+    let (addr, served) = serve(vec![OK, NOT_FOUND]);
+    let other = format!("{}other", addr);
+    let get = |name: &str, url: &str| Request::new(key(name), reqwest::Client::new().get(url));
+    let rsps = fetch_n(&Shared::new(1, Limits::default(), None), vec![
+      get("a", &addr),
+      get("b", &addr).with_part("c", get("b", &other)),
+      get("d", &other),
+    ]).await;
+    assert_eq!(vec!["a", "b", "d"], rsps.iter().map(|r| name(r.key())).collect::<Vec<_>>());
+    assert_eq!(rsps[0].data().as_ref().unwrap(), rsps[1].data().as_ref().unwrap());
+    assert!(rsps[1].part("c").unwrap().data().is_err());
+    assert!(rsps[2].data().is_err());
+    assert_eq!(2, served.try_iter().count());
+  }
+
+  #[tokio::test]
+  async fn authorize_requests() {
+    let (tokens, _) = testing::serve(vec![testing::json(200, r#"{"access_token":"t1","expires_in":3600}"#), testing::json(401, "{}")]);
+    let oauth2 = config::OAuth2::new(&format!("http://{}/oauth/token", tokens), config::Secret::new("abc"), config::Secret::new("xyz"));
+    let (addr, served) = serve(vec![OK]);
+    let get = |name: &str| Request::new(key(name), reqwest::Client::new().get(&addr)).with_oauth2(oauth2.clone());
+    let rsps = fetch_n(&Shared::new(1, Limits::default(), None), vec![get("a")]).await;
+    assert_eq!(b"Ok".as_ref(), rsps[0].data().as_ref().unwrap().as_ref());
+    assert!(served.recv().unwrap().head.to_lowercase().contains("\r\nauthorization: bearer t1\r\n"));
+
+    let oauth2 = config::OAuth2::new(&format!("http://{}/oauth/token", tokens), config::Secret::new("abc"), config::Secret::new("xyz"));
+    let rsps = fetch_n(&Shared::new(1, Limits::default(), None), vec![Request::new(key("b"), reqwest::Client::new().get(&addr)).with_oauth2(oauth2)]).await;
+    assert!(rsps[0].data().is_err());
+    assert_eq!(0, served.try_iter().count());
+  }
+
+  #[tokio::test]
+  async fn cache_responses() {
+    let dir = std::env::temp_dir().join(format!("unfurl-fetch-test-{}", std::process::id()));
+    let cache = cache::Cache::new(&dir);
+    let tagged = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nETag: \"v1\"\r\nContent-Length: 2\r\nConnection: close\r\n\r\nV1";
+    let unchanged = "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+    let (addr, served) = serve(vec![tagged, unchanged]);
+    let get = || vec![Request::new(key("a"), reqwest::Client::new().get(&addr))];
+    for _ in 0..2 {
+      let rsps = fetch_n(&Shared::new(1, Limits::default(), Some(cache.clone())), get()).await;
+      assert_eq!(Some("text/plain"), rsps[0].content_type());
+      assert_eq!(b"V1".as_ref(), rsps[0].data().as_ref().unwrap().as_ref());
+    }
+    assert_eq!(2, served.try_iter().count());
+
+    let (addr, served) = serve(vec![OK]);
+    let get = || vec![Request::new(key("b"), reqwest::Client::new().get(&addr)).with_ttl(time::Duration::from_secs(60))];
+    for _ in 0..2 {
+      let rsps = fetch_n(&Shared::new(1, Limits::default(), Some(cache.clone())), get()).await;
+      assert_eq!(b"Ok".as_ref(), rsps[0].data().as_ref().unwrap().as_ref());
+    }
+    assert_eq!(1, served.try_iter().count());
+    cache.clear().unwrap();
+  }
+
+  #[test]
+  fn parse_rate_limits() {
+    let now = time::UNIX_EPOCH + time::Duration::from_secs(1_700_000_000);
+    let limit = |status: u16, headers: &[(&'static str, &'static str)]| {
+      let mut map = reqwest::header::HeaderMap::new();
+      for (k, v) in headers {
+        map.insert(*k, reqwest::header::HeaderValue::from_static(v));
+      }
+      rate_limit(reqwest::StatusCode::from_u16(status).unwrap(), &map, now)
+    };
+    let secs = time::Duration::from_secs;
+    assert_eq!(Some(Limit::Exceeded(secs(30))), limit(429, &[("retry-after", "30")]));
+    assert_eq!(Some(Limit::Exceeded(secs(60))), limit(429, &[]));
+    assert_eq!(Some(Limit::Exceeded(secs(20))), limit(429, &[("retry-after", "Tue, 14 Nov 2023 22:13:40 GMT")]));
+    assert_eq!(Some(Limit::Exceeded(secs(0))), limit(429, &[("retry-after", "Tue, 14 Nov 2023 22:00:00 GMT")]));
+    assert_eq!(Some(Limit::Exceeded(secs(90))), limit(403, &[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset", "1700000090")]));
+    assert_eq!(Some(Limit::Exceeded(secs(5))), limit(403, &[("retry-after", "5"), ("x-ratelimit-remaining", "12")]));
+    assert_eq!(Some(Limit::Exceeded(secs(40))), limit(429, &[("x-ratelimit-reset", "2023-11-14T22:14:00Z")]));
+    assert_eq!(None, limit(403, &[("x-ratelimit-remaining", "12"), ("x-ratelimit-reset", "1700000090")]));
+    assert_eq!(Some(Limit::Exhausted(secs(90))), limit(200, &[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset", "1700000090")]));
+    assert_eq!(None, limit(200, &[("x-ratelimit-remaining", "1"), ("x-ratelimit-reset", "1700000090")]));
+    assert_eq!(None, limit(503, &[("retry-after", "5")]));
+  }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+use crate::error;
+
+/// A GraphQL query and the values of its variables.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+  text: String,
+  variables: Map<String, Value>,
+}
+
+impl Query {
+  /// Create a query from its text and the values of its variables, which are
+  /// converted to the types they're declared with in the query.
+  pub fn new(text: &str, vars: &HashMap<String, String>) -> Result<Self, error::Error> {
+    let op = Operation::parse(text)?;
+    let mut variables = Map::new();
+    for (name, val) in vars {
+      let decl = op.vars.iter().find(|(n, _)| n == name).map(|(_, d)| *d).unwrap_or("String");
+      variables.insert(name.to_string(), typed_value(name, decl, val)?);
+    }
+    Ok(Self{
+      text: text.to_string(),
+      variables,
+    })
+  }
+
+  /// Determine whether this query can be combined with others by `batch`.
+  pub fn can_batch(&self) -> bool {
+    Operation::parse(&self.text).is_ok_and(|op| op.rest.trim().is_empty())
+  }
+
+  /// The body of a request for this query.
+  pub fn body(&self) -> Value {
+    let mut body = Map::new();
+    body.insert("query".to_string(), Value::from(self.text.as_str()));
+    body.insert("variables".to_string(), Value::Object(self.variables.clone()));
+    Value::Object(body)
+  }
+}
+
+/// Combine several queries into one. The fields each query selects and the
+/// variables it uses are prefixed so that they don't collide, and the
+/// response to the combined query is divided up again by `split`. Queries
+/// which define fragments cannot be combined.
+pub fn batch(queries: &[&Query]) -> Result<Query, error::Error> {
+  let mut decls = Vec::new();
+  let mut sels = Vec::new();
+  let mut variables = Map::new();
+  for (i, query) in queries.iter().enumerate() {
+    let op = Operation::parse(&query.text)?;
+    if !op.rest.trim().is_empty() {
+      return Err(error::Error::Invalid("Cannot batch a GraphQL query which defines fragments".to_string()));
+    }
+    let prefix = prefix(i);
+    for (name, decl) in &op.vars {
+      decls.push(format!("${}{}: {}", prefix, name, decl));
+    }
+    sels.push(rename_vars(&alias_fields(op.selection, &prefix), &prefix));
+    for (name, val) in &query.variables {
+      variables.insert(format!("{}{}", prefix, name), val.clone());
+    }
+  }
+  let text = if decls.is_empty() {
+    format!("query {{\n{}\n}}", sels.join("\n"))
+  } else {
+    format!("query({}) {{\n{}\n}}", decls.join(", "), sels.join("\n"))
+  };
+  Ok(Query{text, variables})
+}
+
+/// Divide the response to a batch of `n` queries into the responses to each
+/// of them. Errors which don't belong to any one query belong to all of them.
+pub fn split(rsp: &Value, n: usize) -> Vec<Value> {
+  (0..n).map(|i| {
+    let prefix = prefix(i);
+    let mut part = Map::new();
+    let data = match rsp.get("data") {
+      Some(Value::Object(data)) => Value::Object(data.iter()
+        .filter_map(|(k, v)| k.strip_prefix(&prefix).map(|k| (k.to_string(), v.clone())))
+        .collect()),
+      _ => Value::Null,
+    };
+    part.insert("data".to_string(), data);
+    let errors: Vec<Value> = match rsp.get("errors") {
+      Some(Value::Array(errors)) => errors.iter().filter_map(|e| {
+        match e.get("path").and_then(|p| p.get(0)).and_then(|p| p.as_str()) {
+          Some(field) => field.strip_prefix(&prefix).map(|field| {
+            let mut e = e.clone();
+            e["path"][0] = Value::from(field);
+            e
+          }),
+          None => Some(e.clone()),
+        }
+      }).collect(),
+      _ => Vec::new(),
+    };
+    if !errors.is_empty() {
+      part.insert("errors".to_string(), Value::Array(errors));
+    }
+    Value::Object(part)
+  }).collect()
+}
+
+/// The data in a response. A response with errors but no data is an error.
+pub fn data(rsp: Value) -> Result<Value, error::Error> {
+  let (data, errors) = match rsp {
+    Value::Object(mut rsp) => (rsp.remove("data"), rsp.remove("errors")),
+    _                      => return Err(error::Error::Invalid("Not a GraphQL response".to_string())),
+  };
+  match (data, errors) {
+    (Some(data), _) if data.as_object().is_some_and(|d| !d.is_empty()) => Ok(data),
+    (_, Some(Value::Array(errors))) if !errors.is_empty() => {
+      let msgs: Vec<&str> = errors.iter().filter_map(|e| e.get("message").and_then(|m| m.as_str())).collect();
+      Err(error::Error::Invalid(format!("GraphQL: {}", msgs.join("; "))))
+    },
+    (Some(data), _) => Ok(data),
+    (None, _)       => Err(error::Error::Invalid("Not a GraphQL response".to_string())),
+  }
+}
+
+fn prefix(i: usize) -> String {
+  format!("q{}_", i)
+}
+
+// Convert the text of a variable to the type it is declared with.
+fn typed_value(name: &str, decl: &str, val: &str) -> Result<Value, error::Error> {
+  let base = decl.split('=').next().unwrap_or("").trim().trim_end_matches('!');
+  let res = match base {
+    "Int"     => val.parse::<i64>().ok().map(Value::from),
+    "Float"   => val.parse::<f64>().ok().map(Value::from),
+    "Boolean" => val.parse::<bool>().ok().map(Value::from),
+    b if b.starts_with('[') => serde_json::from_str(val).ok(),
+    _         => Some(Value::from(val)),
+  };
+  match res {
+    Some(res) => Ok(res),
+    None      => Err(error::Error::Invalid(format!("GraphQL variable ${} is not a valid {}: {}", name, base, val))),
+  }
+}
+
+// An operation: the variables it declares with their types, its selection
+// set without the enclosing braces, and whatever text follows it.
+struct Operation<'a> {
+  vars: Vec<(String, &'a str)>,
+  selection: &'a str,
+  rest: &'a str,
+}
+
+impl<'a> Operation<'a> {
+  fn parse(text: &'a str) -> Result<Self, error::Error> {
+    let invalid = |msg: &str| error::Error::Invalid(format!("Invalid GraphQL query: {}", msg));
+    let b = text.as_bytes();
+    let open = match find_outside(b, 0, |c| c == b'{') {
+      Some(open) => open,
+      None       => return Err(invalid("no selection set")),
+    };
+    let head = text[..open].trim();
+    let head = match head.strip_prefix("query") {
+      Some(head) => head,
+      None if head.is_empty() => head,
+      None => return Err(invalid("only queries are supported")),
+    };
+    let mut vars = Vec::new();
+    if let Some(x) = head.find('(') {
+      let end = match group_end(head.as_bytes(), x) {
+        Some(end) => end,
+        None      => return Err(invalid("unclosed variable definitions")),
+      };
+      for decl in head[x + 1..end - 1].split('$').skip(1) {
+        match decl.split_once(':') {
+          Some((name, decl)) => vars.push((name.trim().to_string(), decl.trim().trim_end_matches(',').trim())),
+          None               => return Err(invalid("variable without a type")),
+        }
+      }
+    }
+    let close = match group_end(b, open) {
+      Some(close) => close,
+      None        => return Err(invalid("unclosed selection set")),
+    };
+    Ok(Self{
+      vars,
+      selection: &text[open + 1..close - 1],
+      rest: &text[close..],
+    })
+  }
+}
+
+fn is_name_start(c: u8) -> bool {
+  c == b'_' || c.is_ascii_alphabetic()
+}
+
+fn is_name(c: u8) -> bool {
+  c == b'_' || c.is_ascii_alphanumeric()
+}
+
+fn name_end(b: &[u8], i: usize) -> usize {
+  b[i..].iter().position(|c| !is_name(*c)).map_or(b.len(), |n| i + n)
+}
+
+// Skip a string or comment starting at the offset, if there is one there.
+fn skip_ignored(b: &[u8], i: usize) -> Option<usize> {
+  match b[i] {
+    b'#' => Some(b[i..].iter().position(|c| *c == b'\n').map_or(b.len(), |n| i + n)),
+    b'"' if b[i..].starts_with(b"\"\"\"") => Some(b[i + 3..].windows(3).position(|w| w == b"\"\"\"").map_or(b.len(), |n| i + n + 6)),
+    b'"' => {
+      let mut j = i + 1;
+      while j < b.len() {
+        match b[j] {
+          b'\\' => j += 2,
+          b'"'  => return Some(j + 1),
+          _     => j += 1,
+        }
+      }
+      Some(b.len())
+    },
+    _ => None,
+  }
+}
+
+fn find_outside<F: Fn(u8) -> bool>(b: &[u8], mut i: usize, f: F) -> Option<usize> {
+  while i < b.len() {
+    if let Some(j) = skip_ignored(b, i) {
+      i = j;
+    } else if f(b[i]) {
+      return Some(i);
+    } else {
+      i += 1;
+    }
+  }
+  None
+}
+
+// Find the end of the bracketed group which opens at the offset, returning
+// the offset just past its close.
+fn group_end(b: &[u8], i: usize) -> Option<usize> {
+  let (open, close) = match b.get(i) {
+    Some(b'(') => (b'(', b')'),
+    Some(b'{') => (b'{', b'}'),
+    Some(b'[') => (b'[', b']'),
+    _          => return None,
+  };
+  let mut depth = 0;
+  let mut i = i;
+  while let Some(j) = find_outside(b, i, |c| c == open || c == close) {
+    if b[j] == open {
+      depth += 1;
+    } else {
+      depth -= 1;
+      if depth == 0 {
+        return Some(j + 1);
+      }
+    }
+    i = j + 1;
+  }
+  None
+}
+
+// Alias each field at the top level of a selection set with the prefix.
+fn alias_fields(sel: &str, prefix: &str) -> String {
+  let b = sel.as_bytes();
+  let mut out = String::new();
+  let mut i = 0;
+  while i < b.len() {
+    let c = b[i];
+    let next = if let Some(j) = skip_ignored(b, i) {
+      j
+    } else if c == b'(' || c == b'{' || c == b'[' {
+      group_end(b, i).unwrap_or(b.len())
+    } else if c == b'@' {
+      name_end(b, i + 1)
+    } else if b[i..].starts_with(b"...") {
+      // a fragment spread or inline fragment, with its type condition
+      let mut j = i + 3;
+      for _ in 0..2 {
+        let k = j + b[j..].iter().take_while(|c| c.is_ascii_whitespace()).count();
+        if k < b.len() && is_name_start(b[k]) {
+          let end = name_end(b, k);
+          let name = &sel[k..end];
+          j = end;
+          if name != "on" {
+            break;
+          }
+        }
+      }
+      j
+    } else if is_name_start(c) {
+      let end = name_end(b, i);
+      let name = &sel[i..end];
+      let k = end + b[end..].iter().take_while(|c| c.is_ascii_whitespace()).count();
+      if b.get(k) == Some(&b':') {
+        // already aliased; replace the alias and keep the field name
+        let k = k + 1 + b[k + 1..].iter().take_while(|c| c.is_ascii_whitespace()).count();
+        let field_end = name_end(b, k);
+        out.push_str(&format!("{}{}{}", prefix, name, &sel[end..field_end]));
+        i = field_end;
+        continue;
+      }
+      out.push_str(&format!("{}{}: {}", prefix, name, name));
+      i = end;
+      continue;
+    } else {
+      i + sel[i..].chars().next().map_or(1, char::len_utf8)
+    };
+    out.push_str(&sel[i..next]);
+    i = next;
+  }
+  out
+}
+
+// Prefix every variable used in the text.
+fn rename_vars(text: &str, prefix: &str) -> String {
+  let b = text.as_bytes();
+  let mut out = String::new();
+  let (mut i, mut start) = (0, 0);
+  while i < b.len() {
+    if let Some(j) = skip_ignored(b, i) {
+      i = j;
+    } else if b[i] == b'$' {
+      out.push_str(&text[start..=i]);
+      out.push_str(prefix);
+      i += 1;
+      start = i;
+    } else {
+      i += 1;
+    }
+  }
+  out.push_str(&text[start..]);
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  const QUERY: &str = r#"query($owner: String!, $repo: String!, $num: Int!) {
+  repository(owner: $owner, name: $repo) { pullRequest(number: $num) { title reviewDecision } }
+  viewer { login } # a comment with a $var, {brace}
+}"#;
+
+  fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+    vars.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+  }
+
+  #[test]
+  fn query_variables() {
+    let query = Query::new(QUERY, &vars(&[("owner", "bww"), ("repo", "unfurl"), ("num", "12")])).unwrap();
+    assert_eq!(json!({
+      "query": QUERY,
+      "variables": {"owner": "bww", "repo": "unfurl", "num": 12},
+    }), query.body());
+    assert!(Query::new(QUERY, &vars(&[("num", "twelve")])).is_err());
+    assert!(Query::new("mutation { addStar { id } }", &vars(&[])).is_err());
+    assert!(Query::new("query { unclosed", &vars(&[])).is_err());
+  }
+
+  #[test]
+  fn batch_queries() {
+    let a = Query::new(QUERY, &vars(&[("owner", "bww"), ("repo", "unfurl"), ("num", "1")])).unwrap();
+    let b = Query::new("{ alias: node(id: \"$not a var\") @include(if: true) { id } ...Spread}", &vars(&[])).unwrap();
+    let query = batch(&[&a, &b]).unwrap();
+    assert_eq!(json!({
+      "query": r#"query($q0_owner: String!, $q0_repo: String!, $q0_num: Int!) {
+
+  q0_repository: repository(owner: $q0_owner, name: $q0_repo) { pullRequest(number: $q0_num) { title reviewDecision } }
+  q0_viewer: viewer { login } # a comment with a $var, {brace}
+
+ q1_alias: node(id: "$not a var") @include(if: true) { id } ...Spread
+}"#,
+      "variables": {"q0_owner": "bww", "q0_repo": "unfurl", "q0_num": 1},
+    }), query.body());
+    let frag = Query::new("query { ...F } fragment F on Query { viewer { login } }", &vars(&[])).unwrap();
+    assert!(batch(&[&a, &frag]).is_err());
+  }
+
+  #[test]
+  fn split_responses() {
+    let rsp = json!({
+      "data": {"q0_repository": {"name": "a"}, "q1_repository": null, "q1_viewer": {"login": "b"}},
+      "errors": [
+        {"message": "Not found", "path": ["q1_repository"]},
+        {"message": "Rate limited"},
+      ],
+    });
+    assert_eq!(vec![
+      json!({"data": {"repository": {"name": "a"}}, "errors": [{"message": "Rate limited"}]}),
+      json!({"data": {"repository": null, "viewer": {"login": "b"}}, "errors": [{"message": "Not found", "path": ["repository"]}, {"message": "Rate limited"}]}),
+      json!({"data": {}, "errors": [{"message": "Rate limited"}]}),
+    ], split(&rsp, 3));
+  }
+
+  #[test]
+  fn response_data() {
+    assert_eq!(json!({"viewer": {"login": "a"}}), data(json!({"data": {"viewer": {"login": "a"}}})).unwrap());
+    assert_eq!(json!({"viewer": null}), data(json!({"data": {"viewer": null}, "errors": [{"message": "Partial"}]})).unwrap());
+    assert_eq!("GraphQL: Bad; Worse", data(json!({"data": null, "errors": [{"message": "Bad"}, {"message": "Worse"}]})).unwrap_err().to_string());
+    assert!(data(json!([])).is_err());
+  }
+
+}
@@ -0,0 +1,571 @@
+//! Unfurl expands links in text and replaces them with useful content.
+//!
+//! ```no_run
+//! let unfurler = unfurl::Unfurler::builder()
+//!   .markdown(true)
+//!   .build()?;
+//! println!("{}", unfurler.unfurl_text("Fixed by https://github.com/bww/unfurl/pull/1"));
+//! # Ok::<(), unfurl::Error>(())
+//! ```
+
+use std::io::Write;
+use std::path;
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+pub mod error;
+pub mod config;
+pub mod parse;
+mod service;
+mod route;
+mod fetch;
+mod template;
+mod graphql;
+mod cache;
+#[cfg(test)]
+mod testing;
+
+use crate::service::Service;
+
+pub use crate::error::Error;
+
+/// What to do with a link that cannot be unfurled.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum OnError {
+  /// Leave the link as it was written
+  #[default]
+  Leave,
+  /// Replace the link with the --fallback format
+  Fallback,
+  /// Leave the link and note the error inline after it
+  Mark,
+}
+
+/// A link which could not be unfurled.
+#[derive(Debug)]
+pub struct Failure {
+  pub url: url::Url,
+  pub err: Error,
+}
+
+/// Text with its links unfurled, and the links which could not be.
+#[derive(Debug)]
+pub struct Unfurled {
+  pub text: String,
+  pub failures: Vec<Failure>,
+}
+
+#[derive(Serialize)]
+struct FailureContext<'a> {
+  url: &'a str,
+  error: String,
+}
+
+/// Text whose links are being fetched.
+pub struct Pending {
+  text: String,
+  rx: oneshot::Receiver<Vec<fetch::Response>>,
+  errors: HashMap<parse::Key, Error>,
+}
+
+// Where routes are defined.
+enum Routes {
+  Path(path::PathBuf),
+  Data(String),
+}
+
+/// Builds an `Unfurler`. Every setting has a default, so an unfurler built
+/// without changing any of them unfurls plain text with the built-in routes.
+pub struct Builder {
+  conf: Option<config::Config>,
+  routes: Vec<Routes>,
+  fetch: Option<config::Fetch>,
+  cache: bool,
+  markdown: bool,
+  keep_links: bool,
+  html: bool,
+  on_error: OnError,
+  fallback: String,
+  notify: bool,
+  debug: bool,
+}
+
+impl Default for Builder {
+  fn default() -> Self {
+    Builder{
+      conf: None,
+      routes: Vec::new(),
+      fetch: None,
+      cache: false,
+      markdown: false,
+      keep_links: false,
+      html: false,
+      on_error: OnError::Leave,
+      fallback: "{url}".to_string(),
+      notify: true,
+      debug: false,
+    }
+  }
+}
+
+impl Builder {
+  /// Use a configuration, which provides credentials, formats and settings
+  /// for services.
+  pub fn config(self, conf: config::Config) -> Self {
+    Builder{conf: Some(conf), ..self}
+  }
+
+  /// Add the routes defined in a file to the built-in routes.
+  pub fn routes_file<P: AsRef<path::Path>>(mut self, path: P) -> Self {
+    self.routes.push(Routes::Path(path.as_ref().to_path_buf()));
+    self
+  }
+
+  /// Add the routes defined in YAML to the built-in routes.
+  pub fn routes(mut self, data: &str) -> Self {
+    self.routes.push(Routes::Data(data.to_string()));
+    self
+  }
+
+  /// Fetch responses with these settings; any which are not set are taken
+  /// from the configuration.
+  pub fn fetch(self, fetch: config::Fetch) -> Self {
+    Builder{fetch: Some(fetch), ..self}
+  }
+
+  /// Cache responses in the user's cache directory.
+  pub fn cache(self, on: bool) -> Self {
+    Builder{cache: on, ..self}
+  }
+
+  /// Treat text as Markdown, so that it remains valid Markdown once it has
+  /// been unfurled.
+  pub fn markdown(self, on: bool) -> Self {
+    Builder{markdown: on, ..self}
+  }
+
+  /// In Markdown, leave `[text](url)` links as they are written.
+  pub fn keep_links(self, on: bool) -> Self {
+    Builder{keep_links: on, ..self}
+  }
+
+  /// Unfurl links which have no route using the metadata in their HTML.
+  pub fn html(self, on: bool) -> Self {
+    Builder{html: on, ..self}
+  }
+
+  pub fn on_error(self, on_error: OnError) -> Self {
+    Builder{on_error, ..self}
+  }
+
+  /// The format used for links which cannot be unfurled with
+  /// `OnError::Fallback`; `{url}` and `{error}` are available.
+  pub fn fallback(self, fallback: &str) -> Self {
+    Builder{fallback: fallback.to_string(), ..self}
+  }
+
+  /// Note on standard error when a host limits the rate of requests and
+  /// they are paused; this is on by default.
+  pub fn notify(self, on: bool) -> Self {
+    Builder{notify: on, ..self}
+  }
+
+  /// Describe how each link is requested on standard error.
+  pub fn debug(self, on: bool) -> Self {
+    Builder{debug: on, ..self}
+  }
+
+  pub fn build(self) -> Result<Unfurler, Error> {
+    // a mistake in the fallback is reported now, rather than once a link
+    // has failed
+    let fallback = template::Template::parse(&self.fallback)
+      .and_then(|tmpl| tmpl.render(&FailureContext{url: "", error: String::new()}).map(|_| tmpl))
+      .map_err(|err| Error::Invalid(format!("Invalid fallback format: {}", err)))?;
+    let mut conf = self.conf.unwrap_or_default();
+    if let Some(fetch) = &self.fetch {
+      let fetch = fetch.or(conf.fetch());
+      conf.set_fetch(fetch);
+    }
+    let mut routes = service::Default::load_default(&conf)?;
+    for src in &self.routes {
+      routes.extend(match src {
+        Routes::Path(path) => service::Default::load_path(&conf, path)?,
+        Routes::Data(data) => service::Default::load_data(&conf, data.as_bytes())?,
+      });
+    }
+    let mut tokenizer = parse::Tokenizer::with_hosts(routes.hosts());
+    tokenizer.set_markdown(self.markdown);
+    let mut svc = service::Chain::new();
+    svc.push(routes);
+    if self.html || conf.html().enabled {
+      svc.push(service::Html::new(&conf));
+    }
+    let cache = match self.cache {
+      true  => cache::Cache::default_dir().map(cache::Cache::new),
+      false => None,
+    };
+    Ok(Unfurler{
+      ftc: fetch::Service::new(fetch::Settings::from(conf.fetch()), cache, self.notify, self.debug),
+      conf,
+      svc,
+      tokenizer,
+      keep_links: self.keep_links,
+      on_error: self.on_error,
+      fallback,
+      debug: self.debug,
+    })
+  }
+}
+
+/// Unfurls the links in text. An unfurler makes its requests on a thread of
+/// its own, which runs until it is dropped.
+pub struct Unfurler {
+  conf: config::Config,
+  svc: service::Chain,
+  tokenizer: parse::Tokenizer,
+  ftc: fetch::Service,
+  keep_links: bool,
+  on_error: OnError,
+  fallback: template::Template,
+  debug: bool,
+}
+
+impl Unfurler {
+  pub fn builder() -> Builder {
+    Builder::default()
+  }
+
+  /// Unfurl the links in text. Links which cannot be unfurled are handled as
+  /// the unfurler was built to handle them; if the links cannot be fetched
+  /// at all, the text is produced as it is. This blocks until every link has
+  /// been fetched, so it must not be called from async code; use
+  /// `unfurl_text_async` there instead.
+  pub fn unfurl_text(&self, text: &str) -> String {
+    match self.unfurl(text) {
+      Ok(res) => res.text,
+      Err(_)  => text.to_string(),
+    }
+  }
+
+  /// Unfurl the links in text, like `unfurl_text`, from async code.
+  pub async fn unfurl_text_async(&self, text: &str) -> String {
+    match self.unfurl_async(text).await {
+      Ok(res) => res.text,
+      Err(_)  => text.to_string(),
+    }
+  }
+
+  /// Unfurl the links in text, producing the links which could not be
+  /// unfurled along with the text.
+  pub fn unfurl(&self, text: &str) -> Result<Unfurled, Error> {
+    let pending = self.fetch(text)?;
+    let mut out = Vec::new();
+    let failures = self.write(&mut out, pending)?;
+    Ok(Unfurled{text: String::from_utf8(out)?, failures})
+  }
+
+  /// Unfurl the links in text, like `unfurl`, from async code. Text which is
+  /// unfurled by several callers at once is fetched at once.
+  pub async fn unfurl_async(&self, text: &str) -> Result<Unfurled, Error> {
+    let (reqs, errors) = self.requests(text);
+    let rsps = self.ftc.fetch_requests(reqs)?.await?;
+    let mut out = Vec::new();
+    let mut failures = Vec::new();
+    self.render(&mut out, text, rsps, errors, &mut failures)?;
+    Ok(Unfurled{text: String::from_utf8(out)?, failures})
+  }
+
+  /// Start fetching the links in text without waiting for them, so that
+  /// several pieces of text can be fetched at once and written in order.
+  /// Each piece is fetched as soon as this is called, alongside any pieces
+  /// which are still being fetched; a slow link in one doesn't delay others.
+  pub fn fetch(&self, text: &str) -> Result<Pending, Error> {
+    let (reqs, errors) = self.requests(text);
+    Ok(Pending{
+      text: text.to_string(),
+      rx: self.ftc.fetch_requests(reqs)?,
+      errors,
+    })
+  }
+
+  /// Wait for the links in text to be fetched and write it out with them
+  /// unfurled, producing the links which could not be unfurled. Like
+  /// `unfurl_text`, this must not be called from async code.
+  pub fn write<W: Write>(&self, out: &mut W, pending: Pending) -> Result<Vec<Failure>, Error> {
+    let rsps = pending.rx.blocking_recv()?;
+    let mut failures = Vec::new();
+    self.render(out, &pending.text, rsps, pending.errors, &mut failures)?;
+    Ok(failures)
+  }
+
+  /// Determine whether text can be unfurled without regard to whatever
+  /// follows it; see `parse::Tokenizer::is_boundary`.
+  pub fn is_boundary(&self, text: &str) -> bool {
+    self.tokenizer.is_boundary(text)
+  }
+
+  // Produce a request for every link in the text that we know how to unfurl,
+  // along with the error for each link that we should be able to unfurl but
+  // can't be requested, like one whose credentials can't be found.
+  fn requests(&self, mut text: &str) -> (Vec<fetch::Request>, HashMap<parse::Key, Error>) {
+    let mut urls: Vec<fetch::Request> = Vec::new();
+    let mut errors: HashMap<parse::Key, Error> = HashMap::new();
+    let mut seen: HashSet<parse::Key> = HashSet::new();
+    loop {
+      let (tok, rest) = self.tokenizer.next(text);
+      match tok {
+        parse::Token::EOF                         => break,
+        parse::Token::Text(_)                     => {},
+        parse::Token::Link{..} if self.keep_links => {},
+        parse::Token::URL(_) | parse::Token::Autolink{..} | parse::Token::Link{..} => {
+          if let Some(key) = tok.key() {
+            // a link is only requested once, however often it appears
+            match self.svc.request(&self.conf, key.url()) {
+              Ok(req) if seen.insert(key.clone()) => {
+                if self.debug {
+                  match req.auth() {
+                    Some(auth) => eprintln!("* * * {}: using credentials from {}", key, auth),
+                    None       => eprintln!("* * * {}: no credentials", key),
+                  }
+                }
+                urls.push(req);
+              },
+              Ok(_) | Err(Error::NotFound) => {},
+              Err(err) => {
+                errors.entry(key).or_insert(err);
+              },
+            }
+          }
+        },
+      };
+      text = rest;
+    }
+    (urls, errors)
+  }
+
+  // Write out the text with each link that was fetched replaced by its
+  // expansion. Links which were not fetched are written as they appear, and
+  // links which could not be requested or expanded are handled according to
+  // on_error.
+  fn render<W: Write>(&self, out: &mut W, mut text: &str, res: Vec<fetch::Response>, mut errors: HashMap<parse::Key, Error>, failures: &mut Vec<Failure>) -> Result<(), Error> {
+    let rsps: HashMap<parse::Key, fetch::Response> = res.into_iter()
+      .map(|e| { (e.key().clone(), e) })
+      .collect();
+    loop {
+      let (tok, rest) = self.tokenizer.next(text);
+      if tok == parse::Token::EOF {
+        break;
+      }
+      text = rest;
+      let key = match tok.key() {
+        Some(key) => key,
+        None      => {
+          write!(out, "{}", tok.source())?;
+          continue;
+        },
+      };
+      let rsp = match rsps.get(&key) {
+        Some(rsp) => rsp,
+        None      => {
+          if let Some(err) = errors.remove(&key) {
+            failures.push(Failure{url: key.url().clone(), err});
+          }
+          match failures.iter().find(|f| f.url == *key.url()) {
+            Some(failure) => self.render_failure(out, &tok, key.url(), &failure.err)?,
+            None          => write!(out, "{}", tok.source())?,
+          }
+          continue;
+        },
+      };
+      if let parse::Token::Link{source, ..} = tok {
+        if self.keep_links {
+          write!(out, "{}", source)?;
+          continue;
+        }
+      }
+      let expanded = match self.svc.format(&self.conf, key.url(), rsp) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+          let res = self.render_failure(out, &tok, key.url(), &err);
+          // a link which appears more than once has failed only once
+          if !failures.iter().any(|f| f.url == *key.url()) {
+            failures.push(Failure{url: key.url().clone(), err});
+          }
+          res?;
+          continue;
+        },
+      };
+      write_expanded(out, &tok, &expanded)?;
+    }
+    Ok(())
+  }
+
+  fn render_failure<W: Write>(&self, out: &mut W, tok: &parse::Token, url: &url::Url, err: &Error) -> Result<(), Error> {
+    match self.on_error {
+      OnError::Leave    => write!(out, "{}", tok.source())?,
+      OnError::Mark     => write!(out, "{} [unfurl error: {}]", tok.source(), err)?,
+      OnError::Fallback => {
+        let text = self.fallback.render(&FailureContext{url: url.as_str(), error: err.to_string()})?;
+        write_expanded(out, tok, &text)?;
+      },
+    };
+    Ok(())
+  }
+}
+
+/// Remove every response from the user's cache.
+pub fn clear_cache() -> Result<(), Error> {
+  match cache::Cache::default_dir() {
+    Some(dir) => cache::Cache::new(dir).clear(),
+    None      => Ok(()),
+  }
+}
+
+// Write the text a link was expanded to in place of the link. A Markdown
+// link keeps its destination, and its title, as they were written.
+fn write_expanded<W: Write>(out: &mut W, tok: &parse::Token, text: &str) -> Result<(), Error> {
+  match tok {
+    parse::Token::Autolink{url, ..} => write!(out, "[{}]({})", escape_link_text(text), url)?,
+    parse::Token::Link{dest, ..}    => write!(out, "[{}]({})", escape_link_text(text), dest)?,
+    _                               => write!(out, "{}", text)?,
+  };
+  Ok(())
+}
+
+fn escape_link_text(text: &str) -> String {
+  text.replace('[', "\\[").replace(']', "\\]")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{mpsc, Arc};
+  use std::sync::atomic::{AtomicBool, Ordering};
+
+  // Serve the same JSON to every request, returning the address served on
+  // and a receiver for each request.
+  fn serve(data: &str) -> (String, mpsc::Receiver<testing::Received>) {
+    testing::serve(vec![testing::json(200, data)])
+  }
+
+  fn unfurler(addr: &str) -> Unfurler {
+    builder(addr).build().unwrap()
+  }
+
+  fn builder(addr: &str) -> Builder {
+    Unfurler::builder()
+      .routes(&format!(r#"
+127.0.0.1:
+  routes:
+    - name: item
+      route: "/items/{{id}}"
+      url: "http://{}/api/items/{{id}}"
+      format: "{{title}} (#{{id}})"
+"#, addr))
+      .on_error(OnError::Mark)
+  }
+
+  #[test]
+  fn unfurl_text() {
+    let (addr, reqs) = serve(r#"{"id": 1, "title": "An item"}"#);
+    let unfurler = unfurler(&addr);
+    let text = format!("See http://{0}/items/1, and again http://{0}/items/1/ at https://example.com/.", addr);
+    let res = unfurler.unfurl(&text).unwrap();
+    assert_eq!("See An item (#1), and again An item (#1) at https://example.com/.", res.text);
+    assert!(res.failures.is_empty());
+    assert_eq!("/api/items/1", reqs.recv().unwrap().path);
+    assert!(reqs.try_recv().is_err());
+    assert_eq!("Nothing to see", unfurler.unfurl_text("Nothing to see"));
+  }
+
+  #[tokio::test]
+  async fn unfurl_text_async() {
+    let (addr, _) = serve("Not JSON");
+    let unfurler = unfurler(&addr);
+    let text = format!("See http://{}/items/2", addr);
+    let res = unfurler.unfurl_async(&text).await.unwrap();
+    assert_eq!(1, res.failures.len());
+    assert!(res.text.starts_with(&format!("{} [unfurl error: ", text)), "Text: {}", res.text);
+    assert_eq!(res.text, unfurler.unfurl_text_async(&text).await);
+  }
+
+  #[test]
+  fn unfurl_markdown() {
+    let (addr, _) = serve(r#"{"id": 1, "title": "An [item]"}"#);
+    let unfurler = builder(&addr).markdown(true).build().unwrap();
+    let text = format!("See [it](<http://{0}/items/1> \"Title\"), <http://{0}/items/1> and\n\n    http://{0}/items/1\n", addr);
+    let expect = format!("See [An \\[item\\] (#1)](<http://{0}/items/1> \"Title\"), [An \\[item\\] (#1)](http://{0}/items/1) and\n\n    http://{0}/items/1\n", addr);
+    assert_eq!(expect, unfurler.unfurl_text(&text));
+  }
+
+  #[test]
+  fn handle_failures() {
+    let (addr, _) = serve("Not JSON");
+    let link = format!("http://{}/items/3", addr);
+    let text = format!("See {0} and [it]({0}).", link);
+    let unfurl = |builder: Builder| builder.markdown(true).build().unwrap().unfurl(&text).unwrap();
+
+    let res = unfurl(builder(&addr).on_error(OnError::Leave));
+    assert_eq!(text, res.text);
+    assert_eq!(1, res.failures.len());
+    assert_eq!(link, res.failures[0].url.as_str());
+
+    let res = unfurl(builder(&addr).on_error(OnError::Mark));
+    let err = res.failures[0].err.to_string();
+    assert_eq!(format!("See {0} [unfurl error: {1}] and [it]({0}) [unfurl error: {1}].", link, err), res.text);
+
+    let res = unfurl(builder(&addr).on_error(OnError::Fallback).fallback("(unavailable: {url})"));
+    assert_eq!(format!("See (unavailable: {0}) and [(unavailable: {0})]({0}).", link), res.text);
+    assert_eq!(1, res.failures.len());
+
+    assert!(builder(&addr).fallback("{url").build().is_err());
+    assert!(builder(&addr).fallback("{link}").build().is_err());
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn unfurl_concurrently() {
+    // the first item is only served once the second has been requested
+    let requested = Arc::new(AtomicBool::new(false));
+    let seen = requested.clone();
+    let (addr, _) = testing::serve_with(move |_, req| {
+      if req.path.ends_with("/2") {
+        seen.store(true, Ordering::SeqCst);
+        return testing::json(200, r#"{"id": 2, "title": "Two"}"#);
+      }
+      let start = std::time::Instant::now();
+      while !seen.load(Ordering::SeqCst) {
+        if start.elapsed() > std::time::Duration::from_secs(5) {
+          return testing::json(500, "{}");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+      }
+      testing::json(200, r#"{"id": 1, "title": "One"}"#)
+    });
+    let unfurler = builder(&addr).cache(false).build().unwrap();
+    let one = format!("http://{}/items/1", addr);
+    let two = format!("http://{}/items/2", addr);
+    let (one, two) = tokio::join!(unfurler.unfurl_text_async(&one), unfurler.unfurl_text_async(&two));
+    assert_eq!(("One (#1)".to_string(), "Two (#2)".to_string()), (one, two));
+    assert!(requested.load(Ordering::SeqCst));
+  }
+
+  #[test]
+  fn handle_request_errors() {
+    let (addr, reqs) = serve(r#"{"id": 1, "title": "An item"}"#);
+    let conf = config::load_data(r#"
+services:
+  127.0.0.1:
+    auth:
+      bearer: ${UNFURL_TEST_UNSET}
+"#.as_bytes()).unwrap();
+    let link = format!("http://{}/items/1", addr);
+    let res = builder(&addr).config(conf).build().unwrap().unfurl(&format!("See {0} and {0}", link)).unwrap();
+    let err = "Environment variable is not set: UNFURL_TEST_UNSET";
+    assert_eq!(format!("See {0} [unfurl error: {1}] and {0} [unfurl error: {1}]", link, err), res.text);
+    assert_eq!(1, res.failures.len());
+    assert_eq!(link, res.failures[0].url.as_str());
+    assert!(reqs.try_recv().is_err());
+  }
+}
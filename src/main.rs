@@ -1,17 +1,14 @@
-use std::io::{Read};
+use std::io::{self, Read, BufRead, Write};
 use std::fs;
-use std::collections::HashMap;
+use std::mem;
+use std::panic;
+use std::process;
+use std::thread;
+use std::sync::mpsc;
 
 use clap::Parser;
 
-mod error;
-mod config;
-mod service;
-mod route;
-mod fetch;
-mod parse;
-
-use crate::service::Service;
+use unfurl::{config, error, Failure, OnError, Pending, Unfurler};
 
 #[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about = None)]
@@ -20,6 +17,24 @@ pub struct Options {
   pub config: Option<String>,
   #[clap(long, help="Use the specified routes definition")]
   pub routes: Option<String>,
+  #[clap(long, help="Treat input as Markdown")]
+  pub markdown: bool,
+  #[clap(long, help="In Markdown mode, leave [text](url) links as they are written")]
+  pub keep_links: bool,
+  #[clap(long, help="Unfurl links that have no route using the metadata in their HTML")]
+  pub html: bool,
+  #[clap(long, help="Unfurl input as it is read, rather than once it has all been read")]
+  pub stream: bool,
+  #[clap(long, value_enum, default_value="leave", help="What to do with a link that cannot be unfurled")]
+  pub on_error: OnErrorOption,
+  #[clap(long, default_value="{url}", help="The format used for links that cannot be unfurled when --on-error=fallback; {url} and {error} are available")]
+  pub fallback: String,
+  #[clap(long, help="Exit with a non-zero status if any link cannot be unfurled")]
+  pub strict: bool,
+  #[clap(long, help="Fetch every response, neither using nor updating the cache")]
+  pub no_cache: bool,
+  #[clap(long, help="Remove every cached response and exit")]
+  pub clear_cache: bool,
   #[clap(long, help="Enable debugging mode")]
   pub debug: bool,
   #[clap(long, help="Enable verbose output")]
@@ -28,15 +43,54 @@ pub struct Options {
   pub file: Option<String>,
 }
 
+/// What to do with a link that cannot be unfurled.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OnErrorOption {
+  /// Leave the link as it was written
+  Leave,
+  /// Replace the link with the --fallback format
+  Fallback,
+  /// Leave the link and note the error inline after it
+  Mark,
+}
+
+impl From<OnErrorOption> for OnError {
+  fn from(opt: OnErrorOption) -> Self {
+    match opt {
+      OnErrorOption::Leave    => OnError::Leave,
+      OnErrorOption::Fallback => OnError::Fallback,
+      OnErrorOption::Mark     => OnError::Mark,
+    }
+  }
+}
+
+const EXIT_ERROR: i32 = 1;
+const EXIT_FAILURES: i32 = 2;
+
 fn main() {
   let opts = Options::parse();
-  match app(&opts) {
-    Ok(_)    => {},
-    Err(err) => eprintln!("* * * {}", err),
+  let res = app(&opts);
+  if let Err(err) = &res {
+    eprintln!("* * * {}", err);
   }
+  process::exit(status(&opts, &res));
 }
 
-fn app(opts: &Options) -> Result<(), error::Error> {
+// The status to exit with: an error is always reported, while links which
+// could not be unfurled are only reported in strict mode.
+fn status(opts: &Options, res: &Result<usize, error::Error>) -> i32 {
+  match res {
+    Err(_)                                       => EXIT_ERROR,
+    Ok(failures) if *failures > 0 && opts.strict => EXIT_FAILURES,
+    Ok(_)                                        => 0,
+  }
+}
+
+fn app(opts: &Options) -> Result<usize, error::Error> {
+  if opts.clear_cache {
+    unfurl::clear_cache()?;
+    return Ok(0);
+  }
   let conf = match config::load(&opts.config) {
     Ok(conf) => conf,
     Err(err) => match err {
@@ -44,64 +98,166 @@ fn app(opts: &Options) -> Result<(), error::Error> {
       err                    => return Err(err),
     },
   };
+  let mut builder = Unfurler::builder()
+    .config(conf)
+    .cache(!opts.no_cache)
+    .markdown(opts.markdown)
+    .keep_links(opts.keep_links)
+    .html(opts.html)
+    .on_error(opts.on_error.into())
+    .fallback(&opts.fallback)
+    .debug(opts.debug);
+  if let Some(routes) = &opts.routes {
+    builder = builder.routes_file(routes);
+  }
+  let unfurler = builder.build()?;
   match &opts.file {
-    Some(path) => unfurl(opts, &conf, fs::File::open(path)?),
-    None       => unfurl(opts, &conf, std::io::stdin()),
+    Some(path) => unfurl(opts, &unfurler, fs::File::open(path)?),
+    None       => unfurl(opts, &unfurler, std::io::stdin()),
+  }
+}
+
+fn unfurl<R: Read>(opts: &Options, unfurler: &Unfurler, r: R) -> Result<usize, error::Error> {
+  let failures = unfurl_input(opts, unfurler, r)?;
+  report(&mut io::stderr(), &failures)?;
+  Ok(failures.len())
+}
+
+// Summarize the links which could not be unfurled.
+fn report<W: Write>(out: &mut W, failures: &[Failure]) -> Result<(), error::Error> {
+  if !failures.is_empty() {
+    writeln!(out, "* * * Could not unfurl {} {}:", failures.len(), if failures.len() == 1 { "link" } else { "links" })?;
+    for failure in failures {
+      writeln!(out, "  {}: {}", failure.url, failure.err)?;
+    }
   }
+  Ok(())
 }
 
-fn unfurl<R: Read>(opts: &Options, conf: &config::Config, mut r: R) -> Result<(), error::Error> {
+fn unfurl_input<R: Read>(opts: &Options, unfurler: &Unfurler, mut r: R) -> Result<Vec<Failure>, error::Error> {
+  if opts.stream {
+    return unfurl_stream(unfurler, io::BufReader::new(r), io::stdout());
+  }
   let mut data = String::new();
   r.read_to_string(&mut data)?;
+  let pending = unfurler.fetch(&data)?;
+  unfurler.write(&mut io::stdout().lock(), pending)
+}
 
-  let ftc = fetch::Service::instance();
-  let svc = {
-    let mut svc = service::Default::load_default(conf)?;
-    if let Some(routes) = &opts.routes {
-      svc.extend(service::Default::load_path(conf, routes)?);
+// Unfurl input one chunk at a time: a line, or in Markdown mode a paragraph
+// or code block. A chunk's links are fetched as soon as it has been read,
+// while its output waits on only those links and whatever preceded it.
+fn unfurl_stream<R: BufRead, W: Write + Send>(unfurler: &Unfurler, mut r: R, mut out: W) -> Result<Vec<Failure>, error::Error> {
+  thread::scope(|s| {
+    let (tx, rx) = mpsc::channel::<Pending>();
+    let printer = s.spawn(move || -> Result<Vec<Failure>, error::Error> {
+      let mut failures = Vec::new();
+      for pending in rx {
+        failures.extend(unfurler.write(&mut out, pending)?);
+        out.flush()?;
+      }
+      Ok(failures)
+    });
+
+    let mut chunk = String::new();
+    loop {
+      let n = r.read_line(&mut chunk)?;
+      if !chunk.is_empty() && (n == 0 || unfurler.is_boundary(&chunk)) {
+        let pending = unfurler.fetch(&mem::take(&mut chunk))?;
+        if tx.send(pending).is_err() {
+          break; // the printer has failed; its error is reported below
+        }
+      }
+      if n == 0 {
+        break;
+      }
     }
-    svc
-  };
 
-  let mut text: &str = &data;
-  let mut toks: Vec<parse::Token> = Vec::new();
-  let mut urls: Vec<fetch::Request> = Vec::new();
-  loop {
-    let (tok, rest) = parse::next(text);
-    match tok {
-      parse::Token::EOF       => break,
-      parse::Token::Text(_)   => toks.push(tok.clone()),
-      parse::Token::URL(text) => match url::Url::parse(text) {
-        Ok(url) => match svc.request(conf, &url) {
-          Ok(req) => {
-            urls.push(fetch::Request::new(text, req));
-            toks.push(tok.clone());
-          },
-          Err(_) => toks.push(parse::Token::Text(text)), // convert to text
-        },
-        Err(_) => toks.push(parse::Token::Text(text)), // convert to text
-      },
-    };
-    text = rest;
+    drop(tx);
+    match printer.join() {
+      Ok(res)  => res,
+      Err(err) => panic::resume_unwind(err),
+    }
+  })
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod testing;
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::time;
+
+  fn failure(url: &str) -> Failure {
+    Failure{url: url::Url::parse(url).unwrap(), err: error::Error::Invalid("Bad data".to_string())}
   }
 
-  let res = ftc.fetch_requests(urls)?.recv()?;
-  let rsps: HashMap<String, fetch::Response> = res.into_iter()
-    .map(|e| { (e.key().to_string(), e) })
-    .collect();
-
-  for tok in &toks {
-    match tok {
-      parse::Token::EOF        => break,
-      parse::Token::Text(text) => print!("{}", text),
-      parse::Token::URL(text)  => {
-        let url = url::Url::parse(text)?;
-        let rsp = rsps.get(&url.to_string()).expect("No respose for URL");
-        print!("{}", svc.format(conf, &url, &rsp)?);
-      },
-    };
+  #[test]
+  fn report_failures() {
+    let mut out = Vec::new();
+    report(&mut out, &[]).unwrap();
+    assert_eq!("", String::from_utf8(out).unwrap());
+    let mut out = Vec::new();
+    report(&mut out, &[failure("https://github.com/a/b/pull/1")]).unwrap();
+    assert_eq!("* * * Could not unfurl 1 link:\n  https://github.com/a/b/pull/1: Bad data\n", String::from_utf8(out).unwrap());
+    let mut out = Vec::new();
+    report(&mut out, &[failure("https://github.com/a/b/pull/1"), failure("https://github.com/a/b/pull/2")]).unwrap();
+    assert!(String::from_utf8(out).unwrap().starts_with("* * * Could not unfurl 2 links:\n"));
   }
 
-  Ok(())
-}
+  #[test]
+  fn exit_status() {
+    let opts = Options::parse_from(["unfurl"]);
+    assert_eq!(0, status(&opts, &Ok(0)));
+    assert_eq!(0, status(&opts, &Ok(2)));
+    assert_eq!(EXIT_ERROR, status(&opts, &Err(error::Error::NotFound)));
+    let opts = Options::parse_from(["unfurl", "--strict"]);
+    assert_eq!(0, status(&opts, &Ok(0)));
+    assert_eq!(EXIT_FAILURES, status(&opts, &Ok(2)));
+    assert_eq!(EXIT_ERROR, status(&opts, &Err(error::Error::NotFound)));
+  }
 
+  #[test]
+  fn stream_chunks() {
+    // the first item is only served once the second has been requested, so
+    // it fails unless the second chunk is fetched while the first is waiting
+    let requested = Arc::new(AtomicBool::new(false));
+    let seen = requested.clone();
+    let (addr, _) = testing::serve_with(move |_, req| {
+      if req.path.ends_with("/2") {
+        seen.store(true, Ordering::SeqCst);
+        return testing::json(200, r#"{"title": "Two"}"#);
+      }
+      let start = time::Instant::now();
+      while !seen.load(Ordering::SeqCst) {
+        if start.elapsed() > time::Duration::from_secs(5) {
+          return testing::json(500, "{}");
+        }
+        thread::sleep(time::Duration::from_millis(10));
+      }
+      testing::json(200, r#"{"title": "One"}"#)
+    });
+    let unfurler = Unfurler::builder()
+      .routes(&format!(r#"
+127.0.0.1:
+  routes:
+    - name: item
+      route: "/items/{{id}}"
+      url: "http://{}/api/items/{{id}}"
+      format: "{{title}}"
+"#, addr))
+      .cache(false)
+      .build()
+      .unwrap();
+    let text = format!("See http://{0}/items/1\nand http://{0}/items/2\n", addr);
+    let mut out = Vec::new();
+    let failures = unfurl_stream(&unfurler, text.as_bytes(), &mut out).unwrap();
+    assert!(failures.is_empty());
+    assert!(requested.load(Ordering::SeqCst));
+    assert_eq!("See One\nand Two\n", String::from_utf8(out).unwrap());
+  }
+}
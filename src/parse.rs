@@ -4,78 +4,559 @@ use std::fmt;
 pub enum Token<'a> {
   Text(&'a str),
   URL(&'a str),
+  Autolink{source: &'a str, url: &'a str},
+  /// An inline link. Its destination is everything between the parentheses
+  /// as written, including any angle brackets and title.
+  Link{source: &'a str, text: &'a str, url: &'a str, dest: &'a str},
   EOF,
 }
 
+impl<'a> Token<'a> {
+  /// The text this token was produced from.
+  pub fn source(&self) -> &'a str {
+    match self {
+      Self::Text(text) => text,
+      Self::URL(url) => url,
+      Self::Autolink{source, ..} => source,
+      Self::Link{source, ..} => source,
+      Self::EOF => "",
+    }
+  }
+
+  pub fn url(&self) -> Option<&'a str> {
+    match self {
+      Self::URL(url) => Some(url),
+      Self::Autolink{url, ..} => Some(url),
+      Self::Link{url, ..} => Some(url),
+      _ => None,
+    }
+  }
+
+  /// The key for the link this token refers to, if it is a link and its URL
+  /// is valid.
+  pub fn key(&self) -> Option<Key> {
+    Key::parse(self.url()?).ok()
+  }
+}
+
+/// Identifies a link by its normalized URL, so that a link can be matched
+/// with the request made for it and the response to that request however
+/// the link was written.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key(url::Url);
+
+impl Key {
+  pub fn parse(text: &str) -> Result<Key, url::ParseError> {
+    normalize(text).map(Key)
+  }
+
+  pub fn url(&self) -> &url::Url {
+    &self.0
+  }
+}
+
+impl From<&url::Url> for Key {
+  fn from(url: &url::Url) -> Self {
+    Key(url.clone())
+  }
+}
+
+impl fmt::Display for Key {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
 impl<'a> fmt::Display for Token<'a> {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     match self {
       Self::Text(text) => write!(f, "{}", text),
       Self::URL(url) => write!(f, "<{}>", url),
+      Self::Autolink{url, ..} => write!(f, "<{}>", url),
+      Self::Link{text, dest, ..} => write!(f, "[{}]({})", text, dest),
       Self::EOF => write!(f, "%"),
     }
   }
 }
 
-pub fn next<'a>(text: &'a str) -> (Token<'a>, &'a str) {
-  if text.len() == 0 {
-    return (Token::EOF, "");
+#[derive(Debug, Default)]
+pub struct Tokenizer {
+  hosts: Vec<String>,
+  markdown: bool,
+}
+
+impl Tokenizer {
+  pub fn with_hosts<I, S>(hosts: I) -> Self
+  where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+  {
+    Self{
+      hosts: hosts.into_iter().filter_map(|h| ascii_host(h.as_ref())).collect(),
+      markdown: false,
+    }
+  }
+
+  /// Treat input as Markdown. Code spans and blocks are passed through as
+  /// text and existing links are produced as `Token::Autolink` and
+  /// `Token::Link` rather than bare URLs.
+  pub fn set_markdown(&mut self, on: bool) {
+    self.markdown = on;
+  }
+
+  /// Determine whether the text can be tokenized without regard to whatever
+  /// follows it. That's the case after a newline or, in Markdown mode, after
+  /// a blank line that isn't within a code block.
+  pub fn is_boundary(&self, text: &str) -> bool {
+    if !self.markdown {
+      return text.ends_with('\n');
+    }
+    let blank = match text.strip_suffix('\n') {
+      Some(body) => body.rsplit('\n').next().unwrap_or("").trim().is_empty(),
+      None       => false,
+    };
+    blank && !is_in_code_block(text)
+  }
+
+  pub fn next<'a>(&self, text: &'a str) -> (Token<'a>, &'a str) {
+    if text.is_empty() {
+      return (Token::EOF, "");
+    }
+    if self.markdown {
+      return self.next_markdown(text);
+    }
+    let x = match self.find_url(text) {
+      Some(x) => x,
+      None    => return (Token::Text(text), ""),
+    };
+    if x > 0 {
+      return (Token::Text(&text[..x]), &text[x..]);
+    }
+    next_url(text)
+  }
+
+  fn next_markdown<'a>(&self, text: &'a str) -> (Token<'a>, &'a str) {
+    let mut prev: Option<char> = None;
+    let mut i = 0;
+    while i < text.len() {
+      let rest = &text[i..];
+      let skip = if prev.is_none() || prev == Some('\n') {
+        code_block_len(rest)
+          .or_else(|| if follows_blank_line(&text[..i]) { indented_code_len(rest) } else { None })
+          .or_else(|| link_definition_len(rest))
+      } else {
+        None
+      };
+      let skip = skip.or_else(|| if rest.starts_with('`') {
+        Some(code_span_len(rest))
+      } else if rest.starts_with("![") {
+        markdown_link(&rest[1..]).map(|(_, n)| n + 1) // images are left alone
+      } else {
+        rest.strip_prefix('\\').map(|c| 1 + c.chars().next().map_or(0, |c| c.len_utf8()))
+      });
+      if let Some(n) = skip {
+        i += n;
+        prev = text[..i].chars().next_back();
+        continue;
+      }
+      if let Some((tok, n)) = markdown_link(rest) {
+        if !tok.url().is_some_and(|url| self.is_url_at(url, None)) {
+          i += n; // some other kind of link; leave it be
+          prev = text[..i].chars().next_back();
+          continue;
+        }
+        if i > 0 {
+          return (Token::Text(&text[..i]), rest);
+        }
+        return (tok, &text[n..]);
+      }
+      if self.is_url_at(rest, prev) {
+        if i > 0 {
+          return (Token::Text(&text[..i]), rest);
+        }
+        return next_url(text);
+      }
+      let c = rest.chars().next().unwrap(); // i < text.len()
+      i += c.len_utf8();
+      prev = Some(c);
+    }
+    (Token::Text(text), "")
+  }
+
+  fn is_url_at(&self, text: &str, prev: Option<char>) -> bool {
+    if text.starts_with("https://") || text.starts_with("http://") {
+      return true;
+    }
+    self.is_bare_at(text, prev)
+  }
+
+  fn find_url(&self, text: &str) -> Option<usize> {
+    let x = find_scheme(text);
+    let y = self.find_bare(text);
+    match (x, y) {
+      (Some(x), Some(y)) => Some(x.min(y)),
+      (x, None)          => x,
+      (None, y)          => y,
+    }
+  }
+
+  // Find the first scheme-less link, like `github.com/org/repo`, whose host
+  // is one we have routes for. Prose that merely mentions a domain is not a
+  // link, so a path is required after the host.
+  fn find_bare(&self, text: &str) -> Option<usize> {
+    if self.hosts.is_empty() {
+      return None;
+    }
+    let mut prev: Option<char> = None;
+    for (i, c) in text.char_indices() {
+      if self.is_bare_at(&text[i..], prev) {
+        return Some(i);
+      }
+      prev = Some(c);
+    }
+    None
   }
-  let x = match text.find("https://") {
-    Some(x) => x,
-    None    => return (Token::Text(text), ""),
+
+  fn is_bare_at(&self, text: &str, prev: Option<char>) -> bool {
+    if let Some(p) = prev {
+      if !is_url_start_boundary(p) {
+        return false;
+      }
+    }
+    if !text.starts_with(char::is_alphanumeric) {
+      return false;
+    }
+    let n = match text.find(|c: char| !is_host_char(c)) {
+      Some(n) => n,
+      None    => return false,
+    };
+    text[n..].starts_with('/') && self.is_known_host(&text[..n])
+  }
+
+  fn is_known_host(&self, host: &str) -> bool {
+    let host = match ascii_host(host) {
+      Some(host) => host,
+      None       => return false,
+    };
+    for known in &self.hosts {
+      if host == *known {
+        return true;
+      }
+      if host.len() > known.len() && host.ends_with(known.as_str()) && host[..host.len() - known.len()].ends_with('.') {
+        return true;
+      }
+    }
+    false
+  }
+}
+
+/// Produce a URL from a token. Scheme-less links are assumed to be HTTPS.
+pub fn normalize(text: &str) -> Result<url::Url, url::ParseError> {
+  match url::Url::parse(text) {
+    // a host and port without a scheme, like `github.com:443/a`, parses as
+    // a URL whose scheme is the host
+    Ok(url) if url.cannot_be_a_base() && is_host_and_port(text) => url::Url::parse(&format!("https://{}", text)),
+    Ok(url) => Ok(url),
+    Err(url::ParseError::RelativeUrlWithoutBase) => url::Url::parse(&format!("https://{}", text)),
+    Err(err) => Err(err),
+  }
+}
+
+// Determine whether text that parses as a URL with an opaque path is really
+// a host without a scheme: either the scheme contains a dot, like a domain
+// does, or it's followed by a port and a path, like `localhost:8080/a`. Other
+// URLs, like `mailto:` and `urn:` URLs, are left as they are.
+fn is_host_and_port(text: &str) -> bool {
+  let (scheme, rest) = match text.split_once(':') {
+    Some(parts) => parts,
+    None        => return false,
   };
-  if x > 0 {
-    return (Token::Text(&text[..x]), &text[x..]);
+  if scheme.contains('.') {
+    return true;
+  }
+  match rest.split_once('/') {
+    Some((port, _)) => !port.is_empty() && port.bytes().all(|c| c.is_ascii_digit()),
+    None            => false,
   }
+}
+
+fn next_url(text: &str) -> (Token<'_>, &str) {
   match next_url_end(text) {
     Some(y) => (Token::URL(&text[..y]), &text[y..]),
     None    => (Token::URL(text), ""),
   }
 }
 
-struct Pos {
-  index: usize,
-  value: char
+// A fenced code block, starting at the beginning of a line and running
+// through its closing fence, or to the end of the input if it's unclosed.
+fn code_block_len(text: &str) -> Option<usize> {
+  code_block(text).map(|(n, _)| n)
+}
+
+// The length of a fenced code block and whether or not it was closed.
+fn code_block(text: &str) -> Option<(usize, bool)> {
+  let indent = text.len() - text.trim_start_matches(' ').len();
+  if indent > 3 {
+    return None;
+  }
+  let fence = &text[indent..];
+  let c = match fence.chars().next() {
+    Some(c) if c == '`' || c == '~' => c,
+    _                               => return None,
+  };
+  let n = fence.len() - fence.trim_start_matches(c).len();
+  if n < 3 {
+    return None;
+  }
+  let mut i = match text.find('\n') {
+    Some(x) => x + 1,
+    None    => return Some((text.len(), false)),
+  };
+  while i < text.len() {
+    let line = &text[i..];
+    let end = line.find('\n').map_or(line.len(), |x| x + 1);
+    let close = line[..end].trim();
+    if close.len() >= n && close.chars().all(|x| x == c) {
+      return Some((i + end, true));
+    }
+    i += end;
+  }
+  Some((text.len(), false))
+}
+
+// An indented code block, made of lines indented by four or more spaces
+// or a tab, and the blank lines between them. Such a block can't interrupt
+// a paragraph, so it must follow a blank line.
+fn indented_code_len(text: &str) -> Option<usize> {
+  let mut n = 0;
+  for line in text.split_inclusive('\n') {
+    if !(is_indented(line) || (n > 0 && line.trim().is_empty())) {
+      break;
+    }
+    n += line.len();
+  }
+  if n > 0 { Some(n) } else { None }
+}
+
+fn is_indented(line: &str) -> bool {
+  let n = line.len() - line.trim_start_matches(' ').len();
+  (n >= 4 || line[n..].starts_with('\t')) && !line.trim().is_empty()
+}
+
+// Determine whether text, which ends at the start of a line, ends with a
+// blank line. The start of the text is taken to follow one.
+fn follows_blank_line(text: &str) -> bool {
+  match text.strip_suffix('\n') {
+    Some(text) => text.rsplit('\n').next().unwrap_or("").trim().is_empty(),
+    None       => text.is_empty(),
+  }
+}
+
+// Determine whether the text ends within a fenced code block.
+fn is_in_code_block(text: &str) -> bool {
+  let mut i = 0;
+  while i < text.len() {
+    let rest = &text[i..];
+    match code_block(rest) {
+      Some((_, false)) => return true,
+      Some((n, true))  => i += n,
+      None             => i += rest.find('\n').map_or(rest.len(), |x| x + 1),
+    }
+  }
+  false
+}
+
+// A link reference definition, like `[label]: https://...`, which is left
+// as written through the end of the line.
+fn link_definition_len(text: &str) -> Option<usize> {
+  let line = text.trim_start_matches(' ');
+  if !line.starts_with('[') {
+    return None;
+  }
+  let x = find_close_bracket(line)?;
+  if !line[x + 1..].starts_with(':') {
+    return None;
+  }
+  Some(text.find('\n').map_or(text.len(), |x| x + 1))
+}
+
+// An inline code span. If the opening backticks are not matched by a run of
+// the same length the backticks are literal, and only they are skipped.
+fn code_span_len(text: &str) -> usize {
+  let n = text.len() - text.trim_start_matches('`').len();
+  let mut i = n;
+  while i < text.len() {
+    let x = match text[i..].find('`') {
+      Some(x) => i + x,
+      None    => break,
+    };
+    let m = text[x..].len() - text[x..].trim_start_matches('`').len();
+    if m == n {
+      return x + m;
+    }
+    i = x + m;
+  }
+  n
+}
+
+// An autolink like `<https://...>` or an inline link like `[text](url)`,
+// along with the number of bytes it occupies.
+fn markdown_link(text: &str) -> Option<(Token<'_>, usize)> {
+  if let Some(inner) = text.strip_prefix('<') {
+    let x = inner.find(|c: char| c == '>' || c == '<' || char::is_whitespace(c))?;
+    if !inner[x..].starts_with('>') || !inner[..x].contains(':') {
+      return None;
+    }
+    return Some((Token::Autolink{source: &text[..x + 2], url: &inner[..x]}, x + 2));
+  }
+  if !text.starts_with('[') {
+    return None;
+  }
+  let x = find_close_bracket(text)?;
+  let label = &text[1..x];
+  let dest = text[x + 1..].strip_prefix('(')?;
+  let dest = dest.trim_start_matches([' ', '\t']);
+  let start = text.len() - dest.len();
+  let (url, n) = if let Some(inner) = dest.strip_prefix('<') {
+    let y = inner.find(['>', '\n'])?;
+    if !inner[y..].starts_with('>') {
+      return None;
+    }
+    (&inner[..y], y + 2)
+  } else {
+    let mut depth = 0;
+    let mut y = dest.len();
+    for (i, c) in dest.char_indices() {
+      if char::is_whitespace(c) || (c == ')' && depth == 0) {
+        y = i;
+        break;
+      }
+      match c {
+        '(' => depth += 1,
+        ')' => depth -= 1,
+        _   => {},
+      }
+    }
+    (&dest[..y], y)
+  };
+  let after = &text[start + n..];
+  let title = after.trim_start();
+  let title = match title.chars().next() {
+    Some(q) if q == '"' || q == '\'' || q == '(' => {
+      let close = if q == '(' { ')' } else { q };
+      let z = title[1..].find(close)?;
+      title[z + 2..].trim_start()
+    },
+    _ => title,
+  };
+  if !title.starts_with(')') {
+    return None;
+  }
+  let end = text.len() - title.len() + 1;
+  let dest = text[start..end - 1].trim_end();
+  Some((Token::Link{source: &text[..end], text: label, url, dest}, end))
+}
+
+fn find_close_bracket(text: &str) -> Option<usize> {
+  let mut depth = 0;
+  let mut escaped = false;
+  for (i, c) in text.char_indices() {
+    if escaped {
+      escaped = false;
+      continue;
+    }
+    match c {
+      '\\' => escaped = true,
+      '['  => depth += 1,
+      ']'  => {
+        depth -= 1;
+        if depth == 0 {
+          return Some(i);
+        }
+      },
+      '\n' if text[i + 1..].starts_with('\n') => return None,
+      _    => {},
+    }
+  }
+  None
 }
 
+fn find_scheme(text: &str) -> Option<usize> {
+  match (text.find("https://"), text.find("http://")) {
+    (Some(x), Some(y)) => Some(x.min(y)),
+    (x, None)          => x,
+    (None, y)          => y,
+  }
+}
+
+fn is_url_start_boundary(c: char) -> bool {
+  char::is_whitespace(c) || c == '(' || c == '[' || c == '<' || c == '"' || c == '\''
+}
+
+fn is_host_char(c: char) -> bool {
+  c.is_alphanumeric() || c == '-' || c == '.'
+}
+
+// Produce the lowercase, ASCII form of a domain name, so that IDN hosts match
+// regardless of whether they're written in Unicode or Punycode.
+fn ascii_host(host: &str) -> Option<String> {
+  match url::Host::parse(host) {
+    Ok(url::Host::Domain(host)) => Some(host),
+    _                           => None,
+  }
+}
+
+// Characters which can never appear in a URL as written in text.
 fn is_url_end(c: char) -> bool {
-  return c == ',' || c == ';' || c == '(' || c == ')' || c == '[' || c == ']' || c == '{' || c == '}' 
+  char::is_whitespace(c) || c == '<' || c == '>' || c == '"' || c == '`' || c == '{' || c == '}'
 }
 
+// Characters which may appear within a URL but, when they trail it, are much
+// more likely to be punctuation belonging to the surrounding text.
 fn is_url_end_maybe(c: char) -> bool {
-  return c == '.' || c == ':'
+  c == '.' || c == ',' || c == ':' || c == ';' || c == '?' || c == '!' || c == '*' || c == '_' || c == '~' || c == '\''
 }
 
+// Find the end of the URL at the start of the text. Brackets are permitted
+// in a URL as long as they are balanced, so a closing bracket that was never
+// opened ends it, as in `(see https://example.com)`.
 fn next_url_end(text: &str) -> Option<usize> {
-  let text = text.chars();
-  let mut p: Option<Pos> = None;
-  for (i, c) in text.enumerate() {
-    if char::is_whitespace(c) {
-      break;
-    }
+  if text.is_empty() {
+    return None;
+  }
+  let mut parens = 0;
+  let mut squares = 0;
+  let mut end = text.len();
+  for (i, c) in text.char_indices() {
     if is_url_end(c) {
-      return Some(i);
+      end = i;
+      break;
     }
-    p = Some(Pos{
-      index: i,
-      value: c,
-    });
-  }
-  match p {
-    Some(p) => if is_url_end_maybe(p.value) {
-      Some(p.index) // index from previous
+    let depth = match c {
+      '(' | ')' => &mut parens,
+      '[' | ']' => &mut squares,
+      _         => continue,
+    };
+    if c == '(' || c == '[' {
+      *depth += 1;
+    } else if *depth > 0 {
+      *depth -= 1;
     } else {
-      Some(p.index + 1) // move forward one, current index
-    },
-    None => None,
+      end = i;
+      break;
+    }
   }
+  Some(text[..end].trim_end_matches(is_url_end_maybe).len())
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
-  
+
+  fn next(text: &str) -> (Token<'_>, &str) {
+    Tokenizer::default().next(text)
+  }
+
   #[test]
   fn parse_text() {
     let text = "Hello, there: https://google.com, and then trailing. Also https://yahoo.com.";
@@ -101,25 +582,310 @@ mod tests {
 
   #[test]
   fn parse_text_boundaries() {
-    let (tok, text) = next("https://google.com,");
-    assert_eq!(Token::URL("https://google.com"), tok);
-    assert_eq!(",", text);
-    let (tok, text) = next("https://google.com, ");
-    assert_eq!(Token::URL("https://google.com"), tok);
-    assert_eq!(", ", text);
-    let (tok, text) = next("https://google.com.");
-    assert_eq!(Token::URL("https://google.com"), tok);
+    for (text, url, rest) in [
+      ("https://google.com,", "https://google.com", ","),
+      ("https://google.com, ", "https://google.com", ", "),
+      ("https://google.com.", "https://google.com", "."),
+      ("https://google.com. ", "https://google.com", ". "),
+      ("https://google.com:", "https://google.com", ":"),
+      ("https://google.com: ", "https://google.com", ": "),
+      ("https://google.com;", "https://google.com", ";"),
+      ("https://google.com...", "https://google.com", "..."),
+      ("https://google.com?", "https://google.com", "?"),
+      ("https://google.com/?q=1!", "https://google.com/?q=1", "!"),
+      ("https://google.com/a?!", "https://google.com/a", "?!"),
+      ("https://google.com/a'", "https://google.com/a", "'"),
+      ("https://google.com/a'.", "https://google.com/a", "'."),
+      ("https://google.com/a\"", "https://google.com/a", "\""),
+      ("https://google.com/a_", "https://google.com/a", "_"),
+      ("https://google.com/a*", "https://google.com/a", "*"),
+      ("https://google.com/a~", "https://google.com/a", "~"),
+      ("https://google.com/a}", "https://google.com/a", "}"),
+      ("https://google.com/a>", "https://google.com/a", ">"),
+      ("https://google.com/a<b", "https://google.com/a", "<b"),
+      ("https://google.com/a`", "https://google.com/a", "`"),
+      ("https://google.com/a,b", "https://google.com/a,b", ""),
+      ("https://google.com/a;b=c", "https://google.com/a;b=c", ""),
+      ("https://google.com/it's", "https://google.com/it's", ""),
+      ("https://google.com/a.b", "https://google.com/a.b", ""),
+      ("https://google.com:443/a", "https://google.com:443/a", ""),
+      ("https://google.com/a\tb", "https://google.com/a", "\tb"),
+      ("https://en.wikipedia.org/wiki/Rust_(programming_language)", "https://en.wikipedia.org/wiki/Rust_(programming_language)", ""),
+      ("https://en.wikipedia.org/wiki/Rust_(programming_language).", "https://en.wikipedia.org/wiki/Rust_(programming_language)", "."),
+      ("https://en.wikipedia.org/wiki/Rust_(programming_language))", "https://en.wikipedia.org/wiki/Rust_(programming_language)", ")"),
+      ("https://en.wikipedia.org/wiki/A_(b_(c)), ok", "https://en.wikipedia.org/wiki/A_(b_(c))", ", ok"),
+      ("https://google.com/a)", "https://google.com/a", ")"),
+      ("https://google.com/a).", "https://google.com/a", ")."),
+      ("https://google.com/a.)", "https://google.com/a", ".)"),
+      ("https://google.com/a?)", "https://google.com/a", "?)"),
+      ("https://google.com/a?q=(1)", "https://google.com/a?q=(1)", ""),
+      ("https://google.com/a[0]", "https://google.com/a[0]", ""),
+      ("https://google.com/a]", "https://google.com/a", "]"),
+      ("https://google.com/a[0)]", "https://google.com/a[0", ")]"),
+    ] {
+      let (tok, text) = next(text);
+      assert_eq!(Token::URL(url), tok);
+      assert_eq!(rest, text);
+    }
+  }
+
+  #[test]
+  fn parse_text_http() {
+    let (tok, text) = next("http://google.com/a, ok");
+    assert_eq!(Token::URL("http://google.com/a"), tok);
+    assert_eq!(", ok", text);
+    let (tok, text) = next("See http://google.com or https://yahoo.com");
+    assert_eq!(Token::Text("See "), tok);
+    let (tok, text) = next(text);
+    assert_eq!(Token::URL("http://google.com"), tok);
+    let (tok, text) = next(text);
+    assert_eq!(Token::Text(" or "), tok);
+    let (tok, _) = next(text);
+    assert_eq!(Token::URL("https://yahoo.com"), tok);
+  }
+
+  #[test]
+  fn parse_text_schemeless() {
+    let tok = Tokenizer::with_hosts(["github.com", "atlassian.net"]);
+    let (t, text) = tok.next("Fixed in github.com/bww/unfurl/pull/5, see www.github.com/bww/unfurl/issues/1.");
+    assert_eq!(Token::Text("Fixed in "), t);
+    let (t, text) = tok.next(text);
+    assert_eq!(Token::URL("github.com/bww/unfurl/pull/5"), t);
+    let (t, text) = tok.next(text);
+    assert_eq!(Token::Text(", see "), t);
+    let (t, text) = tok.next(text);
+    assert_eq!(Token::URL("www.github.com/bww/unfurl/issues/1"), t);
     assert_eq!(".", text);
-    let (tok, text) = next("https://google.com. ");
-    assert_eq!(Token::URL("https://google.com"), tok);
-    assert_eq!(". ", text);
-    let (tok, text) = next("https://google.com:");
-    assert_eq!(Token::URL("https://google.com"), tok);
-    assert_eq!(":", text);
-    let (tok, text) = next("https://google.com: ");
-    assert_eq!(Token::URL("https://google.com"), tok);
-    assert_eq!(": ", text);
+
+    let (t, _) = tok.next("(treno.atlassian.net/browse/TRENO-1)");
+    assert_eq!(Token::Text("("), t);
+    let (t, _) = tok.next("treno.atlassian.net/browse/TRENO-1");
+    assert_eq!(Token::URL("treno.atlassian.net/browse/TRENO-1"), t);
+
+    // unknown hosts, hosts without a path and partial host matches are text
+    assert_eq!(Token::Text("example.com/a/b"), tok.next("example.com/a/b").0);
+    assert_eq!(Token::Text("We like github.com."), tok.next("We like github.com.").0);
+    assert_eq!(Token::Text("notgithub.com/a/b"), tok.next("notgithub.com/a/b").0);
+    assert_eq!(Token::Text("a.github.com.evil/x"), tok.next("a.github.com.evil/x").0);
   }
 
-}
+  fn collect<'a>(tok: &Tokenizer, mut text: &'a str) -> Vec<Token<'a>> {
+    let mut toks = Vec::new();
+    loop {
+      let (t, rest) = tok.next(text);
+      if t == Token::EOF {
+        return toks;
+      }
+      toks.push(t);
+      text = rest;
+    }
+  }
+
+  #[test]
+  fn parse_markdown() {
+    let mut tok = Tokenizer::with_hosts(["github.com"]);
+    tok.set_markdown(true);
+
+    assert_eq!(vec![
+      Token::Text("See "),
+      Token::Link{source: "[the PR](https://github.com/a/b/pull/1)", text: "the PR", url: "https://github.com/a/b/pull/1", dest: "https://github.com/a/b/pull/1"},
+      Token::Text(" and "),
+      Token::Autolink{source: "<https://github.com/a/b/issues/2>", url: "https://github.com/a/b/issues/2"},
+      Token::Text(" and "),
+      Token::URL("https://github.com/a/b/issues/3"),
+      Token::Text("."),
+    ], collect(&tok, "See [the PR](https://github.com/a/b/pull/1) and <https://github.com/a/b/issues/2> and https://github.com/a/b/issues/3."));
 
+    assert_eq!(vec![
+      Token::Link{source: "[x [y]](<https://github.com/a/b/pull/1> \"Title\")", text: "x [y]", url: "https://github.com/a/b/pull/1", dest: "<https://github.com/a/b/pull/1> \"Title\""},
+    ], collect(&tok, "[x [y]](<https://github.com/a/b/pull/1> \"Title\")"));
+
+    assert_eq!(vec![
+      Token::Link{source: "[s](github.com/a/b/pull/1)", text: "s", url: "github.com/a/b/pull/1", dest: "github.com/a/b/pull/1"},
+    ], collect(&tok, "[s](github.com/a/b/pull/1)"));
+
+    assert_eq!(vec![
+      Token::Link{source: "[s]( <https://github.com/a/b/blob/main/a b.md>\t'Title' )", text: "s", url: "https://github.com/a/b/blob/main/a b.md", dest: "<https://github.com/a/b/blob/main/a b.md>\t'Title'"},
+    ], collect(&tok, "[s]( <https://github.com/a/b/blob/main/a b.md>\t'Title' )"));
+  }
+
+  #[test]
+  fn parse_markdown_passthrough() {
+    let mut tok = Tokenizer::with_hosts(["github.com"]);
+    tok.set_markdown(true);
+    for text in [
+      "Run `curl https://github.com/a/b` now",
+      "Run ``a ` https://github.com/a/b`` now",
+      "```\nhttps://github.com/a/b\n```\n",
+      "  ~~~~ sh\ncurl https://github.com/a/b\n~~~\nstill code https://github.com/a/b\n~~~~\n",
+      "```\nunclosed https://github.com/a/b\n",
+      "![image](https://github.com/a/b.png)",
+      "[docs](./docs/README.md)",
+      "[ref]: https://github.com/a/b/pull/1\n",
+      "<mailto:someone@example.com>",
+      "    curl https://github.com/a/b\n",
+      "Some code:\n\n\tcurl https://github.com/a/b\n\n    curl https://github.com/a/c\n",
+    ] {
+      assert_eq!(vec![Token::Text(text)], collect(&tok, text), "Expected text: {:?}", text);
+    }
+
+    assert_eq!(vec![
+      Token::Text("`unclosed "),
+      Token::URL("https://github.com/a/b"),
+    ], collect(&tok, "`unclosed https://github.com/a/b"));
+
+    assert_eq!(vec![
+      Token::Text("```\ncode\n```\nThen "),
+      Token::URL("github.com/a/b"),
+    ], collect(&tok, "```\ncode\n```\nThen github.com/a/b"));
+
+    // an indented line continues a paragraph, and code ends with its indent
+    assert_eq!(vec![
+      Token::Text("Some text\n    "),
+      Token::URL("https://github.com/a/b"),
+      Token::Text("\n\n    code\n\nThen "),
+      Token::URL("https://github.com/a/c"),
+    ], collect(&tok, "Some text\n    https://github.com/a/b\n\n    code\n\nThen https://github.com/a/c"));
+  }
+
+  #[test]
+  fn parse_text_multibyte() {
+    let (tok, text) = next("Voilà: https://bücher.example/straße_(ß)…");
+    assert_eq!(Token::Text("Voilà: "), tok);
+    let (tok, text) = next(text);
+    assert_eq!(Token::URL("https://bücher.example/straße_(ß)…"), tok);
+    assert_eq!("", text);
+    let (tok, text) = next("🚀 https://google.com/🚀, 🚀");
+    assert_eq!(Token::Text("🚀 "), tok);
+    let (tok, text) = next(text);
+    assert_eq!(Token::URL("https://google.com/🚀"), tok);
+    assert_eq!(", 🚀", text);
+    let (tok, text) = next("https://google.com/é. next");
+    assert_eq!(Token::URL("https://google.com/é"), tok);
+    assert_eq!(". next", text);
+  }
+
+  #[test]
+  fn parse_text_idn() {
+    let tok = Tokenizer::with_hosts(["bücher.example", "xn--caf-dma.example"]);
+    let (t, text) = tok.next("Über bücher.example/a/b und café.example/c.");
+    assert_eq!(Token::Text("Über "), t);
+    let (t, text) = tok.next(text);
+    assert_eq!(Token::URL("bücher.example/a/b"), t);
+    let (t, text) = tok.next(text);
+    assert_eq!(Token::Text(" und "), t);
+    let (t, text) = tok.next(text);
+    assert_eq!(Token::URL("café.example/c"), t);
+    assert_eq!(".", text);
+    let (t, _) = tok.next("xn--bcher-kva.example/a");
+    assert_eq!(Token::URL("xn--bcher-kva.example/a"), t);
+    assert_eq!("https://xn--bcher-kva.example/a/b", normalize("bücher.example/a/b").unwrap().as_str());
+  }
+
+  #[test]
+  fn text_boundaries() {
+    let tok = Tokenizer::default();
+    assert!(tok.is_boundary("A line\n"));
+    assert!(tok.is_boundary("\n"));
+    assert!(!tok.is_boundary("A line"));
+    assert!(!tok.is_boundary("https://github.com/a/"));
+
+    let mut tok = Tokenizer::default();
+    tok.set_markdown(true);
+    assert!(tok.is_boundary("A paragraph\n\n"));
+    assert!(tok.is_boundary("A paragraph\n  \n"));
+    assert!(tok.is_boundary("\n"));
+    assert!(!tok.is_boundary("A [link\n"));
+    assert!(!tok.is_boundary("A paragraph\n"));
+    assert!(!tok.is_boundary("```\ncode\n\n"));
+    assert!(!tok.is_boundary("Some\n\n~~~~\ncode\n~~~\n\n"));
+    assert!(tok.is_boundary("```\ncode\n```\n\n"));
+    assert!(tok.is_boundary("Some\n\n~~~~\ncode\n~~~~~\nMore\n\n"));
+  }
+
+  #[test]
+  fn normalize_url() {
+    assert_eq!("https://github.com/a/b", normalize("github.com/a/b").unwrap().as_str());
+    assert_eq!("http://github.com/a/b", normalize("http://github.com/a/b").unwrap().as_str());
+    assert_eq!("https://www.github.com/a/b", normalize("www.github.com/a/b").unwrap().as_str());
+    assert_eq!("https://github.com:8443/a/b", normalize("github.com:8443/a/b").unwrap().as_str());
+    assert_eq!("https://localhost:8080/a", normalize("localhost:8080/a").unwrap().as_str());
+    for text in ["mailto:someone@example.com", "tel:+15555550123", "tel:5555550123", "urn:isbn:0451450523"] {
+      assert_eq!(text, normalize(text).unwrap().as_str());
+    }
+  }
+
+  #[test]
+  fn link_keys() {
+    let key = Key::parse("https://github.com/").unwrap();
+    for text in ["https://github.com", "HTTPS://GitHub.COM/", "https://github.com:443/", "github.com", "GITHUB.com:443"] {
+      assert_eq!(key, Key::parse(text).unwrap(), "Link: {}", text);
+    }
+    assert_ne!(key, Key::parse("http://github.com/").unwrap());
+    assert_ne!(key, Key::parse("https://github.com:8443/").unwrap());
+    assert_eq!(Key::parse("https://example.com/a%20b").unwrap(), Key::parse("https://example.com/a b").unwrap());
+    assert_eq!(Key::parse("https://xn--bcher-kva.example/a").unwrap(), Key::parse("bücher.example/a").unwrap());
+    assert_eq!(Some(key.clone()), Token::Link{source: "[a](https://GitHub.com)", text: "a", url: "https://GitHub.com", dest: "https://GitHub.com"}.key());
+    assert_eq!(Some(key.clone()), Key::parse(key.url().as_str()).ok());
+    assert_eq!(None, Token::Text("https://github.com/").key());
+    assert_eq!(None, Token::URL("https://").key());
+  }
+
+  mod fuzz {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn input() -> impl Strategy<Value = String> {
+      let frag = prop_oneof![
+        Just("https://".to_string()),
+        Just("http://".to_string()),
+        Just("github.com/".to_string()),
+        Just("bücher.example/".to_string()),
+        Just("[".to_string()),
+        Just("](".to_string()),
+        Just(")".to_string()),
+        Just("<".to_string()),
+        Just(">".to_string()),
+        Just("`".to_string()),
+        Just("```\n".to_string()),
+        Just("![".to_string()),
+        Just("\\".to_string()),
+        Just("\n".to_string()),
+        Just(" ".to_string()),
+        Just(".,;:?!'\"".to_string()),
+        Just("ß…🚀".to_string()),
+        any::<char>().prop_map(|c| c.to_string()),
+        "[a-z0-9/]{1,8}",
+      ];
+      prop_oneof![
+        prop::collection::vec(frag, 0..24).prop_map(|v| v.concat()),
+        any::<String>(),
+      ]
+    }
+
+    fn tokens(tok: &Tokenizer, mut text: &str) -> Vec<String> {
+      let mut toks = Vec::new();
+      loop {
+        let (t, rest) = tok.next(text);
+        if t == Token::EOF {
+          assert_eq!("", rest);
+          return toks;
+        }
+        assert!(!t.source().is_empty(), "Empty token for: {:?}", text);
+        assert_eq!(text.len(), t.source().len() + rest.len());
+        toks.push(t.source().to_string());
+        text = rest;
+      }
+    }
+
+    proptest! {
+      #[test]
+      fn tokens_concatenate_to_input(text in input(), markdown in any::<bool>()) {
+        let mut tok = Tokenizer::with_hosts(["github.com", "bücher.example"]);
+        tok.set_markdown(markdown);
+        prop_assert_eq!(text.clone(), tokens(&tok, &text).concat());
+      }
+    }
+  }
+
+}
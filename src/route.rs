@@ -61,7 +61,15 @@ impl Pattern {
       };
       let ln = lc.len();
       if ln > 2 && &lc[0..1] == "{" && &lc[ln-1..ln] == "}" {
-        vars.insert(lc[1..ln-1].to_string(), rc.to_string());
+        // a variable may be constrained to digits, like `{id:int}`, or to
+        // anything but some words, like `{user:!about|search}`
+        let name = match lc[1..ln-1].split_once(':') {
+          Some((name, "int")) => if rc.bytes().all(|c| c.is_ascii_digit()) { name } else { return None; },
+          Some((name, not)) if not.starts_with('!') => if not[1..].split('|').all(|w| w != rc) { name } else { return None; },
+          Some(_)             => return None,
+          None                => &lc[1..ln-1],
+        };
+        vars.insert(name.to_string(), rc.to_string());
       }else if lc != rc {
         return None; // match failed
       }
@@ -115,13 +123,23 @@ mod tests {
 
     let p = Pattern::_new("/");
     assert_eq!(Some(Match::new_empty()), p.match_path("/"));
-    let p = Pattern::new("a/b");
+    let p = Pattern::_new("a/b");
     assert_eq!(Some(Match::new_empty()), p.match_path("a/b"));
-    let p = Pattern::new("/a/b");
+    let p = Pattern::_new("/a/b");
     assert_eq!(Some(Match::new_empty()), p.match_path("/a/b"));
-    let p = Pattern::new("a/{b}");
+    let p = Pattern::_new("a/{b}");
     assert_eq!(Some(Match::new(HashMap::from([("b".to_string(), "Hello".to_string())]))), p.match_path("a/Hello"));
 
+    let p = Pattern::_new("/v/{id:int}");
+    assert_eq!(Some(Match::new(HashMap::from([("id".to_string(), "123".to_string())]))), p.match_path("/v/123"));
+    assert_eq!(None, p.match_path("/v/about"));
+    assert_eq!(None, p.match_path("/v/12a"));
+
+    let p = Pattern::_new("/{user:!discover|search}/{track}");
+    assert_eq!(Some(Match::new(HashMap::from([("user".to_string(), "someone".to_string()), ("track".to_string(), "a-track".to_string())]))), p.match_path("/someone/a-track"));
+    assert_eq!(None, p.match_path("/discover/sets"));
+    assert_eq!(None, p.match_path("/search/sounds"));
+
     let p = Pattern::_new("/{a}/{b}");
     assert_eq!(Some(Match::new(HashMap::from([
       ("a".to_string(), "Anything".to_string()),
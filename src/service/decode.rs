@@ -0,0 +1,197 @@
+use quick_xml::events::{BytesStart, Event};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+
+use crate::error;
+
+/// How a response body is turned into the value that formats are rendered
+/// with. An endpoint which doesn't declare a decoder has one chosen by the
+/// Content-Type of its response.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Decoder {
+  /// The body is JSON
+  Json,
+  /// The body is XML; elements become objects keyed by their children's
+  /// names, with attributes as `@name` and text as `#text`
+  Xml,
+  /// The body is YAML
+  Yaml,
+  /// The body is an HTML page, described by its title, OpenGraph and
+  /// Twitter card metadata
+  HtmlMeta,
+  /// The body is plain text, available as `text`
+  Text,
+}
+
+impl Decoder {
+  /// Choose a decoder for a Content-Type. Anything we don't recognize is
+  /// treated as JSON, which is what most APIs produce.
+  pub fn detect(content_type: Option<&str>) -> Decoder {
+    let mime = content_type.unwrap_or("").split(';').next().unwrap_or("").trim().to_lowercase();
+    match mime.as_str() {
+      "text/html" | "application/xhtml+xml"                        => Decoder::HtmlMeta,
+      m if m.ends_with("/json") || m.ends_with("+json")            => Decoder::Json,
+      m if m.ends_with("/xml") || m.ends_with("+xml")              => Decoder::Xml,
+      m if m.ends_with("yaml")                                     => Decoder::Yaml,
+      m if m.starts_with("text/")                                  => Decoder::Text,
+      _                                                            => Decoder::Json,
+    }
+  }
+
+  pub fn decode(&self, data: &[u8]) -> Result<Value, error::Error> {
+    match self {
+      Decoder::Json     => serde_json::from_slice(data).map_err(invalid),
+      Decoder::Yaml     => serde_yaml::from_slice(data).map_err(invalid),
+      Decoder::Xml      => xml(data),
+      Decoder::HtmlMeta => Ok(super::html::metadata(&String::from_utf8_lossy(data))),
+      Decoder::Text     => {
+        let mut text = Map::new();
+        text.insert("text".to_string(), Value::from(String::from_utf8_lossy(data).trim()));
+        Ok(Value::Object(text))
+      },
+    }
+  }
+}
+
+fn invalid<E: std::fmt::Display>(err: E) -> error::Error {
+  error::Error::Invalid(format!("Could not parse data: {}", err))
+}
+
+// An element which has been opened but not yet closed.
+struct Element {
+  name: String,
+  fields: Map<String, Value>,
+  text: String,
+}
+
+impl Element {
+  fn new(start: &BytesStart) -> Result<Self, error::Error> {
+    let mut fields = Map::new();
+    for attr in start.attributes() {
+      let attr = attr.map_err(invalid)?;
+      let key = format!("@{}", String::from_utf8_lossy(attr.key.as_ref()));
+      fields.insert(key, Value::from(attr.unescape_value().map_err(invalid)?.as_ref()));
+    }
+    Ok(Self{
+      name: String::from_utf8_lossy(start.name().as_ref()).to_string(),
+      fields,
+      text: String::new(),
+    })
+  }
+
+  // An element with neither attributes nor children is just its text.
+  fn into_value(self) -> Value {
+    if self.fields.is_empty() {
+      return Value::from(self.text);
+    }
+    let mut fields = self.fields;
+    if !self.text.is_empty() {
+      fields.insert("#text".to_string(), Value::from(self.text));
+    }
+    Value::Object(fields)
+  }
+
+  // Add a child; children which share a name are collected into a list.
+  fn insert(&mut self, name: String, value: Value) {
+    match self.fields.get_mut(&name) {
+      Some(Value::Array(list)) => list.push(value),
+      Some(prev)               => *prev = Value::Array(vec![prev.take(), value]),
+      None                     => { self.fields.insert(name, value); },
+    }
+  }
+}
+
+fn xml(data: &[u8]) -> Result<Value, error::Error> {
+  let mut reader = quick_xml::Reader::from_reader(data);
+  reader.trim_text(true);
+  let mut stack = vec![Element{name: String::new(), fields: Map::new(), text: String::new()}];
+  let mut buf = Vec::new();
+  loop {
+    match reader.read_event_into(&mut buf).map_err(invalid)? {
+      Event::Start(e) => stack.push(Element::new(&e)?),
+      Event::Empty(e) => {
+        let elem = Element::new(&e)?;
+        let name = elem.name.clone();
+        stack.last_mut().unwrap().insert(name, elem.into_value());
+      },
+      Event::Text(e)  => stack.last_mut().unwrap().text.push_str(&e.unescape().map_err(invalid)?),
+      Event::CData(e) => stack.last_mut().unwrap().text.push_str(&String::from_utf8_lossy(&e)),
+      Event::End(_)   => {
+        let elem = match stack.pop() {
+          Some(elem) if !stack.is_empty() => elem,
+          _                               => return Err(invalid("unexpected end of element")),
+        };
+        let name = elem.name.clone();
+        stack.last_mut().unwrap().insert(name, elem.into_value());
+      },
+      Event::Eof      => break,
+      _               => {},
+    }
+    buf.clear();
+  }
+  match stack.pop() {
+    Some(root) if stack.is_empty() => Ok(Value::Object(root.fields)),
+    _                              => Err(invalid("unexpected end of document")),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn detect_decoders() {
+    for (content_type, expect) in [
+      (None, Decoder::Json),
+      (Some("application/json; charset=utf-8"), Decoder::Json),
+      (Some("application/vnd.github+json"), Decoder::Json),
+      (Some("application/rss+xml"), Decoder::Xml),
+      (Some("Text/XML"), Decoder::Xml),
+      (Some("application/yaml"), Decoder::Yaml),
+      (Some("text/html; charset=utf-8"), Decoder::HtmlMeta),
+      (Some("text/plain"), Decoder::Text),
+      (Some("application/octet-stream"), Decoder::Json),
+    ] {
+      assert_eq!(expect, Decoder::detect(content_type), "Content-Type: {:?}", content_type);
+    }
+  }
+
+  #[test]
+  fn decode_values() {
+    assert_eq!(json!({"title": "A title", "tags": ["a", "b"]}), Decoder::Json.decode(br#"{"title": "A title", "tags": ["a", "b"]}"#).unwrap());
+    assert_eq!(json!({"title": "A title", "tags": ["a", "b"]}), Decoder::Yaml.decode(b"title: A title\ntags: [a, b]\n").unwrap());
+    assert_eq!(json!({"text": "Some text"}), Decoder::Text.decode(b"  Some text\n").unwrap());
+    assert_eq!(json!({"title": "A title", "og": {}, "twitter": {}}), Decoder::HtmlMeta.decode(b"<html><head><title>A title</title></head></html>").unwrap());
+    assert!(Decoder::Json.decode(b"<html>").is_err());
+  }
+
+  #[test]
+  fn decode_xml() {
+    let data = br#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0">
+  <channel>
+    <title>A feed &amp; more</title>
+    <item><title>First</title><link>https://example.com/1</link></item>
+    <item><title><![CDATA[Second <b>]]></title><enclosure url="https://example.com/2.mp3" length="5"/></item>
+  </channel>
+</rss>"#;
+    assert_eq!(json!({
+      "rss": {
+        "@version": "2.0",
+        "channel": {
+          "title": "A feed & more",
+          "item": [
+            {"title": "First", "link": "https://example.com/1"},
+            {"title": "Second <b>", "enclosure": {"@url": "https://example.com/2.mp3", "@length": "5"}},
+          ],
+        },
+      },
+    }), Decoder::Xml.decode(data).unwrap());
+    assert_eq!(json!({"a": {"@b": "c", "#text": "d"}}), Decoder::Xml.decode(br#"<a b="c">d</a>"#).unwrap());
+    assert!(Decoder::Xml.decode(b"<a><b></a>").is_err());
+    assert!(Decoder::Xml.decode(b"<a>").is_err());
+  }
+
+}
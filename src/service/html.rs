@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use serde_json::{Map, Value};
+
+use crate::error;
+use crate::config;
+use crate::fetch;
+use crate::parse;
+
+use super::{Service, VERSION};
+
+const NAME: &str = "html";
+const DEFAULT_FORMAT: &str = "{title}";
+
+/// A service which unfurls any web page using the metadata in its HTML: its
+/// `<title>` and its OpenGraph and Twitter card `<meta>` tags. Formats are
+/// rendered with `title`, `description`, `site_name` and `image`, which are
+/// taken from whichever of these is available, as well as `og.*` and
+/// `twitter.*` with the tags exactly as they appear in the page.
+pub struct Html {
+  client: reqwest::Client,
+}
+
+impl Html {
+  pub fn new(conf: &config::Config) -> Self {
+    Self{
+      client: fetch::Settings::from(conf.fetch()).client(),
+    }
+  }
+}
+
+impl Service for Html {
+  fn request(&self, conf: &config::Config, link: &url::Url) -> Result<fetch::Request, error::Error> {
+    let settings = fetch::Settings::from(&conf.fetch_for(link.host_str().unwrap_or("")));
+    match link.scheme() {
+      "http" | "https" => Ok(fetch::Request::new(parse::Key::from(link), self.client.get(link.as_str())
+        .header("User-Agent", &format!("Unfurl/{}", VERSION))
+        .header("Accept", "text/html,application/xhtml+xml")).with_settings(settings)),
+      _ => Err(error::Error::NotFound),
+    }
+  }
+
+  fn format(&self, conf: &config::Config, link: &url::Url, rsp: &fetch::Response) -> Result<String, error::Error> {
+    let data = match rsp.data() {
+      Ok(data) => data,
+      Err(err) => return Err(error::Error::Invalid(format!("Could not read data: {}", err))),
+    };
+    let meta = metadata(&String::from_utf8_lossy(data));
+    let host = link.host_str().unwrap_or("");
+    let mut formats: Vec<&str> = Vec::new();
+    if let Some(format) = conf.service_or_default(host).format(NAME).or(conf.html().format.as_ref()) {
+      formats.extend(format.templates().iter().map(|f| f.as_str()));
+    }
+    formats.push(DEFAULT_FORMAT);
+    super::format_value(&meta, &[super::link_var(link)], NAME, &formats)
+  }
+}
+
+// Extract metadata from a page.
+pub(super) fn metadata(html: &str) -> Value {
+  let mut og = Map::new();
+  let mut twitter = Map::new();
+  let mut title: Option<String> = None;
+  let mut description: Option<String> = None;
+
+  let mut rest = html;
+  while let Some(x) = rest.find('<') {
+    rest = &rest[x + 1..];
+    let end = match tag_end(rest) {
+      Some(end) => end,
+      None      => break,
+    };
+    let tag = &rest[..end];
+    let name = tag.split(|c: char| c.is_whitespace() || c == '/').next().unwrap_or("").to_lowercase();
+    match name.as_str() {
+      "title" if title.is_none() => {
+        let body = &rest[end + 1..];
+        if let Some(y) = find_ignore_case(body, "</title") {
+          title = Some(decode(&body[..y]));
+        }
+      },
+      "meta" => {
+        let attrs = attributes(tag);
+        let key = attrs.get("property").or(attrs.get("name")).map(|k| k.to_lowercase());
+        if let (Some(key), Some(content)) = (key, attrs.get("content")) {
+          if let Some(key) = key.strip_prefix("og:") {
+            og.entry(key).or_insert(Value::from(content.as_str()));
+          } else if let Some(key) = key.strip_prefix("twitter:") {
+            twitter.entry(key).or_insert(Value::from(content.as_str()));
+          } else if key == "description" && description.is_none() {
+            description = Some(content.to_string());
+          }
+        }
+      },
+      "/head" | "body" => break,
+      _ => {},
+    }
+    rest = &rest[end + 1..];
+  }
+
+  let pick = |keys: &[(&Map<String, Value>, &str)]| -> Option<Value> {
+    keys.iter().find_map(|(m, k)| m.get(*k).filter(|v| v.as_str().is_some_and(|v| !v.trim().is_empty())).cloned())
+  };
+  let mut meta = Map::new();
+  let title = pick(&[(&og, "title"), (&twitter, "title")]).or(title.map(|t| Value::from(t.trim())));
+  let description = pick(&[(&og, "description"), (&twitter, "description")]).or(description.map(Value::from));
+  let site_name = pick(&[(&og, "site_name"), (&twitter, "site")]);
+  let image = pick(&[(&og, "image"), (&twitter, "image")]);
+  for (key, val) in [("title", title), ("description", description), ("site_name", site_name), ("image", image)] {
+    if let Some(val) = val {
+      meta.insert(key.to_string(), val);
+    }
+  }
+  meta.insert("og".to_string(), Value::Object(og));
+  meta.insert("twitter".to_string(), Value::Object(twitter));
+  Value::Object(meta)
+}
+
+// Find the end of a tag, ignoring any `>` within quoted attribute values.
+fn tag_end(text: &str) -> Option<usize> {
+  let mut quote: Option<char> = None;
+  for (i, c) in text.char_indices() {
+    match (quote, c) {
+      (None, '>')                => return Some(i),
+      (None, '"') | (None, '\'') => quote = Some(c),
+      (Some(q), c) if q == c     => quote = None,
+      _                          => {},
+    }
+  }
+  None
+}
+
+// Parse the attributes of a tag, excluding the name of the tag itself.
+fn attributes(tag: &str) -> HashMap<String, String> {
+  let mut attrs = HashMap::new();
+  let mut rest = tag.trim_start_matches(|c: char| !c.is_whitespace());
+  loop {
+    rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+    if rest.is_empty() {
+      break;
+    }
+    let x = rest.find(|c: char| c.is_whitespace() || c == '=' || c == '/').unwrap_or(rest.len());
+    let name = rest[..x].to_lowercase();
+    rest = rest[x..].trim_start();
+    let value = match rest.strip_prefix('=') {
+      Some(more) => {
+        let more = more.trim_start();
+        let (value, more) = match more.chars().next() {
+          Some(q) if q == '"' || q == '\'' => match more[1..].find(q) {
+            Some(y) => (&more[1..y + 1], &more[y + 2..]),
+            None    => (&more[1..], ""),
+          },
+          _ => {
+            let y = more.find(char::is_whitespace).unwrap_or(more.len());
+            (&more[..y], &more[y..])
+          },
+        };
+        rest = more;
+        decode(value)
+      },
+      None => String::new(),
+    };
+    attrs.entry(name).or_insert(value);
+  }
+  attrs
+}
+
+// Decode the character references we're likely to encounter in metadata.
+fn decode(text: &str) -> String {
+  let mut out = String::new();
+  let mut rest = text;
+  while let Some(x) = rest.find('&') {
+    out.push_str(&rest[..x]);
+    rest = &rest[x..];
+    let end = match rest.find(';') {
+      Some(end) if end <= 10 => end,
+      _                      => {
+        out.push('&');
+        rest = &rest[1..];
+        continue;
+      },
+    };
+    let decoded = match &rest[1..end] {
+      "amp"  => Some('&'),
+      "lt"   => Some('<'),
+      "gt"   => Some('>'),
+      "quot" => Some('"'),
+      "apos" => Some('\''),
+      "nbsp" => Some('\u{a0}'),
+      r => if let Some(hex) = r.strip_prefix("#x").or(r.strip_prefix("#X")) {
+        u32::from_str_radix(hex, 16).ok().and_then(char::from_u32)
+      } else if let Some(dec) = r.strip_prefix('#') {
+        dec.parse::<u32>().ok().and_then(char::from_u32)
+      } else {
+        None
+      },
+    };
+    match decoded {
+      Some(c) => {
+        out.push(c);
+        rest = &rest[end + 1..];
+      },
+      None => {
+        out.push('&');
+        rest = &rest[1..];
+      },
+    }
+  }
+  out.push_str(rest);
+  out.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn find_ignore_case(text: &str, pattern: &str) -> Option<usize> {
+  let n = pattern.len();
+  text.char_indices()
+    .find(|(i, _)| text.get(*i..*i + n).is_some_and(|s| s.eq_ignore_ascii_case(pattern)))
+    .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn extract_metadata() {
+    let meta = metadata(r#"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <TITLE>
+    The  page &amp; title
+  </TITLE>
+  <meta name="description" content="A plain -> description">
+  <meta property="og:title" content="The OpenGraph title" />
+  <meta property='og:site_name' content='Example'>
+  <meta name="twitter:card" content="summary">
+  <meta name="twitter:title" content="The Twitter title">
+  <meta name="twitter:description" content="The &quot;Twitter&quot; description &#x2014; &#8230;">
+  <meta content="https://example.com/image.png" property="og:image">
+</head>
+<body><meta property="og:title" content="Not this one"></body>
+</html>"#);
+    assert_eq!(json!({
+      "title": "The OpenGraph title",
+      "description": "The \"Twitter\" description — …",
+      "site_name": "Example",
+      "image": "https://example.com/image.png",
+      "og": {
+        "title": "The OpenGraph title",
+        "site_name": "Example",
+        "image": "https://example.com/image.png",
+      },
+      "twitter": {
+        "card": "summary",
+        "title": "The Twitter title",
+        "description": "The \"Twitter\" description — …",
+      },
+    }), meta);
+  }
+
+  #[test]
+  fn extract_title() {
+    let meta = metadata("<html><head><title>Just a &lt;title&gt; &amp more</title><meta name=description content=Bare><meta name='x' content=\"It's > that\"></head></html>");
+    assert_eq!(json!({
+      "title": "Just a <title> &amp more",
+      "description": "Bare",
+      "og": {},
+      "twitter": {},
+    }), meta);
+    assert_eq!(json!({"og": {}, "twitter": {}}), metadata("Not HTML at all"));
+    assert_eq!(json!({"og": {}, "twitter": {}}), metadata("<html><title>Unclosed"));
+  }
+
+}
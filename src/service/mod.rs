@@ -1,34 +1,223 @@
 use std::fs;
+use std::fmt;
 use std::path;
+use std::time;
 use std::io::Read;
 use std::collections::HashMap;
 
 use serde::{Serialize, Deserialize};
 use serde_yaml;
 use reqwest;
-use addr;
 
 use crate::error;
 use crate::config::{self, Authenticator};
 use crate::fetch;
+use crate::graphql;
+use crate::parse;
 use crate::route;
+use crate::template;
+
+mod decode;
+mod html;
+pub use decode::Decoder;
+pub use html::Html;
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 const DEFAULT_FORMAT: &str = "<NO FORMAT AVAILABLE>";
 const BUILTIN_ROUTES: &str = include_str!("../../conf/routes.yml");
 
-pub trait Service {
-  fn request(&self, conf: &config::Config, link: &url::Url) -> Result<reqwest::RequestBuilder, error::Error>;
+pub trait Service: Send + Sync {
+  fn request(&self, conf: &config::Config, link: &url::Url) -> Result<fetch::Request, error::Error>;
   fn format(&self, conf: &config::Config, link: &url::Url, rsp: &fetch::Response) -> Result<String, error::Error>;
 }
 
+/// A service which defers to each of its services in turn, using the first
+/// one that can unfurl a link.
+pub struct Chain {
+  services: Vec<Box<dyn Service>>,
+}
+
+impl Chain {
+  pub fn new() -> Self {
+    Self{
+      services: Vec::new(),
+    }
+  }
+
+  pub fn push<S: Service + 'static>(&mut self, svc: S) {
+    self.services.push(Box::new(svc));
+  }
+}
+
+impl Service for Chain {
+  fn request(&self, conf: &config::Config, link: &url::Url) -> Result<fetch::Request, error::Error> {
+    for svc in &self.services {
+      match svc.request(conf, link) {
+        Err(error::Error::NotFound) => continue,
+        res                         => return res,
+      }
+    }
+    Err(error::Error::NotFound)
+  }
+
+  fn format(&self, conf: &config::Config, link: &url::Url, rsp: &fetch::Response) -> Result<String, error::Error> {
+    for svc in &self.services {
+      match svc.format(conf, link, rsp) {
+        Err(error::Error::NotFound) => continue,
+        res                         => return res,
+      }
+    }
+    Err(error::Error::NotFound)
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+enum Method {
+  #[default]
+  Get,
+  Post,
+}
+
+impl From<Method> for reqwest::Method {
+  fn from(method: Method) -> Self {
+    match method {
+      Method::Get  => reqwest::Method::GET,
+      Method::Post => reqwest::Method::POST,
+    }
+  }
+}
+
+/// A GraphQL query made for a link. Each variable is a template which is
+/// rendered with the link's route variables.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Graphql {
+  query: String,
+  #[serde(default)]
+  variables: HashMap<String, String>,
+}
+
+/// A request made for a link: either the request for a route, or one of the
+/// parts which are requested along with it.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Call {
+  url: Option<String>,
+  oembed: Option<String>,
+  #[serde(default)]
+  method: Method,
+  body: Option<serde_json::Value>,
+  graphql: Option<Graphql>,
+  decode: Option<Decoder>,
+  ttl: Option<u64>,
+}
+
+impl Call {
+  fn url(&self, name: &str, link: &url::Url, cxt: &HashMap<String, String>) -> Result<String, error::Error> {
+    if let Some(oembed) = &self.oembed {
+      // the endpoint may give the link itself, as `{url}`, and the format;
+      // whichever it doesn't give are added
+      let mut cxt = cxt.clone();
+      cxt.insert("url".to_string(), url::form_urlencoded::byte_serialize(link.as_str().as_bytes()).collect());
+      let mut url = url::Url::parse(&template::render(oembed, &cxt)?)?;
+      let given: Vec<String> = url.query_pairs().map(|(k, _)| k.to_string()).collect();
+      for (name, val) in [("url", link.as_str()), ("format", "json")] {
+        if !given.iter().any(|k| k == name) {
+          url.query_pairs_mut().append_pair(name, val);
+        }
+      }
+      return Ok(url.into());
+    }
+    match &self.url {
+      Some(url) => Ok(template::render(url, cxt)?),
+      None      => Err(error::Error::Invalid(format!("No URL for route: {}", name))),
+    }
+  }
+
+  // Check that the call describes a single request, so that a route which
+  // doesn't is reported when it's loaded rather than when it's used.
+  fn validate(&self, name: &str) -> Result<(), error::Error> {
+    match (&self.url, &self.oembed) {
+      (Some(_), Some(_)) => return Err(error::Error::Invalid(format!("Route has both a url and an oembed endpoint: {}", name))),
+      (None, None)       => return Err(error::Error::Invalid(format!("Route has neither a url nor an oembed endpoint: {}", name))),
+      _                  => {},
+    }
+    if self.body.is_some() && self.graphql.is_some() {
+      return Err(error::Error::Invalid(format!("Route has both a body and a GraphQL query: {}", name)));
+    }
+    Ok(())
+  }
+
+  fn method(&self) -> Method {
+    match self.graphql {
+      Some(_) => Method::Post,
+      None    => self.method,
+    }
+  }
+
+  // Decode a response, with the declared decoder if there is one, to the
+  // value that formats are rendered with.
+  fn decode(&self, rsp: &fetch::Response) -> Result<serde_json::Value, error::Error> {
+    let data = match rsp.data() {
+      Ok(data) => data,
+      Err(err) => return Err(error::Error::Invalid(format!("Could not read data: {}", err))),
+    };
+    let decoder = self.decode.unwrap_or_else(|| Decoder::detect(rsp.content_type()));
+    let data = decoder.decode(data.as_ref())?;
+    match self.graphql {
+      Some(_) => graphql::data(data),
+      None    => Ok(data),
+    }
+  }
+}
+
+/// A request made along with the request for a route, the result of which
+/// is available to formats under its name.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Part {
+  name: String,
+  #[serde(flatten)]
+  call: Call,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 struct Endpoint {
   name: String,
   route: route::Pattern,
-  url: String,
-  format: String,
+  #[serde(flatten)]
+  call: Call,
+  format: config::Format,
+  #[serde(default)]
+  extract: Extract,
+  #[serde(default)]
+  requests: Vec<Part>,
+}
+
+/// Named JMESPath expressions which extract values from a response. They're
+/// compiled when routes are loaded, so that an invalid one is reported then.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "HashMap<String, String>", into = "HashMap<String, String>")]
+struct Extract(Vec<(String, jmespath::Expression<'static>)>);
+
+impl TryFrom<HashMap<String, String>> for Extract {
+  type Error = String;
+
+  fn try_from(exprs: HashMap<String, String>) -> Result<Self, Self::Error> {
+    let mut compiled = Vec::new();
+    for (name, expr) in exprs {
+      match jmespath::compile(&expr) {
+        Ok(expr) => compiled.push((name, expr)),
+        Err(err) => return Err(format!("Invalid expression for {}: {}", name, err)),
+      }
+    }
+    Ok(Extract(compiled))
+  }
+}
+
+impl From<Extract> for HashMap<String, String> {
+  fn from(exprs: Extract) -> Self {
+    exprs.0.into_iter().map(|(name, expr)| (name, expr.as_str().to_string())).collect()
+  }
 }
 
 impl Endpoint {
@@ -36,59 +225,63 @@ impl Endpoint {
     &self.name
   }
 
-  fn url(&self, link: &url::Url, mat: &route::Match) -> Result<String, error::Error> {
-    let cxt = match link.host() {
+  fn vars(&self, link: &url::Url, mat: &route::Match) -> HashMap<String, String> {
+    match link.host() {
       Some(host) => mat.vars_with(HashMap::from([("domain".to_string(), host.to_string())])),
       None       => mat.vars.clone(),
-    };
-    let mut f = tinytemplate::TinyTemplate::new();
-    f.add_template(&self.name, &self.url)?;
-    Ok(f.render(&self.name, &cxt)?)
+    }
   }
 
-  fn format<'a>(&'a self) -> Option<&'a str> {
-    Some(&self.format)
+  fn format<'a>(&'a self) -> &'a config::Format {
+    &self.format
   }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Domain {
+  #[serde(skip)]
+  name: String,
   config: Option<config::Service>,
+  #[serde(default)]
   headers: HashMap<String, String>,
   routes: Vec<Endpoint>,
+  #[serde(skip)]
+  client: reqwest::Client,
 }
 
 impl Domain {
-  fn set_config(&mut self, conf: config::Service) {
-    self.config = Some(conf);
+  fn set_client(&mut self, client: reqwest::Client) {
+    self.client = client;
   }
 
-  fn format<'a>(&'a self, name: &str) -> Option<&'a str> {
-    match &self.config {
-      Some(conf) => conf.format(name),
-      None       => None,
-    }
-  }
 }
 
-impl config::Authenticator for &Domain {
-  fn authenticate(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
-    match &self.config {
-      Some(conf) => conf.authenticate_chain::<&Domain>(req, None),
-      None       => req,
-    }
-  }
+/// Where the settings used for a link were found.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Origin {
+  /// The configuration for the link's host.
+  Host(String),
+  /// The configuration for a domain the link's host belongs to, like
+  /// `*.atlassian.net` or `atlassian.net`.
+  Parent(String),
+  /// The domain in the routes which the link matched.
+  Routes(String),
+  /// The default configuration.
+  Default,
+}
 
-  fn authenticate_chain<A: config::Authenticator>(&self, req: reqwest::RequestBuilder, next: Option<A>) -> reqwest::RequestBuilder {
-    match &self.config {
-      Some(conf) => conf.authenticate_chain(req, next),
-      None       => req,
+impl fmt::Display for Origin {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Host(host)     => write!(f, "the configuration for {}", host),
+      Self::Parent(domain) => write!(f, "the configuration for {}", domain),
+      Self::Routes(domain) => write!(f, "the routes for {}", domain),
+      Self::Default        => write!(f, "the default configuration"),
     }
   }
 }
 
 pub struct Default {
-  client: reqwest::Client,
   domains: HashMap<String, Domain>,
 }
 
@@ -106,12 +299,16 @@ impl Default {
     r.read_to_string(&mut data)?;
     let mut domains: HashMap<String, Domain> = serde_yaml::from_str(&data)?;
     for (k, v) in domains.iter_mut() {
-      if let Some(svc) = conf.service(k) {
-        v.set_config(svc.clone());
+      for ept in &v.routes {
+        ept.call.validate(&ept.name)?;
+        for part in &ept.requests {
+          part.call.validate(&format!("{}.{}", ept.name, part.name))?;
+        }
       }
+      v.name = k.clone();
+      v.set_client(fetch::Settings::from(&conf.fetch_for(k)).client());
     }
     Ok(Self{
-      client: reqwest::Client::new(),
       domains: domains,
     })
   }
@@ -121,17 +318,31 @@ impl Default {
       Some(host) => host,
       None       => return None,
     };
-    if let Some(endpoint) = self.domains.get(host) {
-      return Some(endpoint);
+    config::host_names(host).iter().find_map(|name| self.domains.get(name))
+  }
+
+  // The settings which apply to a link, most specific first: those for its
+  // host, those for the domains it belongs to, those given with the routes
+  // it matched, and finally the defaults.
+  fn services<'a>(&'a self, conf: &'a config::Config, host: &str, domain: &'a Domain) -> Vec<(Origin, &'a config::Service)> {
+    let mut svcs = Vec::new();
+    for (name, svc) in conf.services_for(host) {
+      match name == host {
+        true  => svcs.push((Origin::Host(name.to_string()), svc)),
+        false => svcs.push((Origin::Parent(name.to_string()), svc)),
+      }
     }
-    let root = match addr::parse_domain_name(host) {
-      Ok(addr) => match addr.root() {
-        Some(root) => root,
-        None       => host, // weird; just use the input host
-      },
-      Err(_) => return None,
-    };
-    self.domains.get(root)
+    if let Some(svc) = &domain.config {
+      svcs.push((Origin::Routes(domain.name.clone()), svc));
+    }
+    svcs.push((Origin::Default, conf.default_service()));
+    svcs
+  }
+
+  // The settings used to authenticate requests for a link, which are the
+  // most specific settings that provide credentials.
+  fn authority<'a>(&'a self, conf: &'a config::Config, host: &str, domain: &'a Domain) -> Option<(Origin, &'a config::Service)> {
+    self.services(conf, host, domain).into_iter().find(|(_, svc)| svc.auth.is_some())
   }
 
   fn find_route<'a>(&'a self, url: &url::Url) -> Option<(&'a Domain, &'a Endpoint, route::Match)> {
@@ -147,29 +358,81 @@ impl Default {
     None
   }
 
+  /// The domains which have routes, in order and each only once; a wildcard
+  /// domain is given as the domain it matches the subdomains of.
+  pub fn hosts(&self) -> Vec<&str> {
+    let mut hosts: Vec<&str> = self.domains.keys().map(|k| k.trim_start_matches("*.")).collect();
+    hosts.sort();
+    hosts.dedup();
+    hosts
+  }
+
   pub fn extend(&mut self, another: Default) {
     self.domains.extend(another.domains.into_iter())
   }
 
-  fn get(&self, conf: &config::Service, domain: &Domain, url: &str) -> reqwest::RequestBuilder {
-    let mut builder = self.client.get(url)
+  fn request_for(&self, auth: Option<&(Origin, &config::Service)>, domain: &Domain, name: &str, call: &Call, link: &url::Url, cxt: &HashMap<String, String>) -> Result<fetch::Request, error::Error> {
+    let mut builder = domain.client.request(call.method().into(), call.url(name, link, cxt)?)
       .header("User-Agent", &format!("Unfurl/{}", VERSION));
     for (key, val) in &domain.headers {
       builder = builder.header(key, val);
     }
-    conf.authenticate_chain(builder, Some(domain))
+    let mut query = None;
+    if let Some(gql) = &call.graphql {
+      let mut vars = HashMap::new();
+      for (name, val) in &gql.variables {
+        vars.insert(name.to_string(), template::render(val, cxt)?);
+      }
+      let q = graphql::Query::new(&gql.query, &vars)?;
+      builder = builder.json(&q.body());
+      query = Some(q);
+    } else if let Some(body) = &call.body {
+      builder = match render_body(body, cxt)? {
+        serde_json::Value::String(body) => builder.body(body),
+        body                            => builder.json(&body),
+      };
+    }
+    let mut req = match auth {
+      Some((origin, svc)) => {
+        let mut req = fetch::Request::new(parse::Key::from(link), svc.authenticate(builder)?).with_auth(&origin.to_string());
+        if let Some(credentials) = svc.credentials()? {
+          req = req.with_credentials(&format!("{}: {}", origin, credentials));
+        }
+        match svc.oauth2() {
+          Some(oauth2) => req.with_oauth2(oauth2.clone()),
+          None         => req,
+        }
+      },
+      None => fetch::Request::new(parse::Key::from(link), builder),
+    };
+    if let Some(ttl) = call.ttl {
+      req = req.with_ttl(time::Duration::from_secs(ttl));
+    }
+    match query {
+      Some(query) => Ok(req.with_graphql(query)),
+      None        => Ok(req),
+    }
   }
 }
 
 impl Service for Default {
-  fn request(&self, conf: &config::Config, link: &url::Url) -> Result<reqwest::RequestBuilder, error::Error> {
+  fn request(&self, conf: &config::Config, link: &url::Url) -> Result<fetch::Request, error::Error> {
     let host = match link.host_str() {
       Some(host) => host,
       None       => return Err(error::Error::Invalid("No host".to_string())),
     };
     match self.find_route(link) {
-      Some((domain, ept, mat)) => Ok(self.get(conf.service_or_default(host), domain, &ept.url(link, &mat)?)),
-      None                     => Err(error::Error::NotFound),
+      Some((domain, ept, mat)) => {
+        let auth = self.authority(conf, host, domain);
+        let settings = fetch::Settings::from(&conf.fetch_for(host));
+        let cxt = ept.vars(link, &mat);
+        let mut req = self.request_for(auth.as_ref(), domain, ept.name(), &ept.call, link, &cxt)?.with_settings(settings.clone());
+        for part in &ept.requests {
+          req = req.with_part(&part.name, self.request_for(auth.as_ref(), domain, &part.name, &part.call, link, &cxt)?.with_settings(settings.clone()));
+        }
+        Ok(req)
+      },
+      None => Err(error::Error::NotFound),
     }
   }
 
@@ -181,29 +444,381 @@ impl Service for Default {
     match self.find_route(link) {
       Some((dom, ept, _)) => {
         let name = ept.name();
-        let svc = conf.service_or_default(host);
-        let format = svc.format(name)
-          .or(dom.format(name))
-          .or(ept.format())
-          .or(Some(DEFAULT_FORMAT));
-        Ok(format_response(rsp, name, format.unwrap())?)
+        let mut formats: Vec<&str> = Vec::new();
+        if let Some(format) = self.services(conf, host, dom).into_iter().find_map(|(_, svc)| svc.format(name)) {
+          formats.extend(format.templates().iter().map(|f| f.as_str()));
+        }
+        formats.extend(ept.format().templates().iter().map(|f| f.as_str()));
+        if formats.is_empty() {
+          formats.push(DEFAULT_FORMAT);
+        }
+        let mut data = ept.call.decode(rsp)?;
+        for part in &ept.requests {
+          // a part which could not be fetched is left out, so that formats
+          // which use it can fall back
+          let val = match rsp.part(&part.name).map(|rsp| part.call.decode(rsp)) {
+            Some(Ok(val)) => val,
+            _             => continue,
+          };
+          match data.as_object_mut() {
+            Some(obj) => obj.insert(part.name.clone(), val),
+            None      => return Err(error::Error::Invalid(format!("Cannot add {} to a response which is not an object", part.name))),
+          };
+        }
+        let mut vars = vec![link_var(link)];
+        vars.extend(extract(&data, &ept.extract)?);
+        format_value(&data, &vars, name, &formats)
       },
       None => Err(error::Error::NotFound),
     }
   }
 }
 
-fn format_response(rsp: &fetch::Response, name: &str, format: &str) -> Result<String, error::Error> {
-  let data = match rsp.data() {
-    Ok(data) => data,
-    Err(err) => return Err(error::Error::Invalid(format!("Could not read data: {}", err))),
-  };
-  let rsp: serde_json::Value = match serde_json::from_slice(data.as_ref()) {
-    Ok(rsp)  => rsp,
-    Err(err) => return Err(error::Error::Invalid(format!("Could not parse data: {}", err))),
-  };
-  let mut f = tinytemplate::TinyTemplate::new();
-  f.add_template(&name, &format)?;
-  Ok(f.render(&name, &rsp)?)
+// Render every string in a request body as a template.
+fn render_body(body: &serde_json::Value, cxt: &HashMap<String, String>) -> Result<serde_json::Value, error::Error> {
+  Ok(match body {
+    serde_json::Value::String(s) => serde_json::Value::String(template::render(s, cxt)?),
+    serde_json::Value::Array(a)  => serde_json::Value::Array(a.iter().map(|v| render_body(v, cxt)).collect::<Result<_, _>>()?),
+    serde_json::Value::Object(o) => serde_json::Value::Object(o.iter().map(|(k, v)| Ok((k.clone(), render_body(v, cxt)?))).collect::<Result<_, error::Error>>()?),
+    v                            => v.clone(),
+  })
+}
+
+// Evaluate JMESPath expressions against the data. Expressions which produce
+// nothing are omitted, so that a format which uses them can fall back.
+fn extract(data: &serde_json::Value, exprs: &Extract) -> Result<Vec<(String, serde_json::Value)>, error::Error> {
+  let mut vars = Vec::new();
+  for (name, expr) in &exprs.0 {
+    let res = expr.search(data)?;
+    if !res.is_null() {
+      vars.push((name.to_string(), serde_json::to_value(&*res)?));
+    }
+  }
+  Ok(vars)
+}
+
+// The link being unfurled, which every format can use as `link`. A value
+// in the response with the same name is still available as `@root.link`.
+pub(super) fn link_var(link: &url::Url) -> (String, serde_json::Value) {
+  ("link".to_string(), serde_json::Value::String(link.to_string()))
+}
+
+// Render the first of the formats that can be rendered with the data; the
+// error produced by the last one is returned if none of them can be.
+fn format_value(data: &serde_json::Value, vars: &[(String, serde_json::Value)], name: &str, formats: &[&str]) -> Result<String, error::Error> {
+  let mut res = Err(error::Error::Invalid(format!("No format for: {}", name)));
+  for format in formats {
+    res = match template::Template::parse(format) {
+      Ok(tmpl) => tmpl.render_value_with(data, vars.to_vec()).map_err(|e| e.into()),
+      Err(err) => Err(err.into()),
+    };
+    if res.is_ok() {
+      break;
+    }
+  }
+  res
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn format_fallbacks() {
+    let data = serde_json::json!({"number": 5, "title": "A title", "fields": {"summary": "A summary"}});
+    assert_eq!("A title (#5)", format_value(&data, &[], "pr", &["{title} (#{number})", "{number}"]).unwrap());
+    assert_eq!("A summary", format_value(&data, &[], "pr", &["{missing}", "{fields.missing}", "{fields.summary}", "{title}"]).unwrap());
+    assert_eq!("Unavailable", format_value(&data, &[], "pr", &["{missing}", "Unavailable"]).unwrap());
+    assert_eq!("5", format_value(&data, &[], "pr", &["{unclosed", "{number}"]).unwrap());
+    assert!(format_value(&data, &[], "pr", &["{missing}", "{fields.missing}"]).is_err());
+    assert!(format_value(&data, &[], "pr", &[]).is_err());
+  }
+
+  #[test]
+  fn extract_values() {
+    let data = serde_json::json!({
+      "key": "TRENO-1",
+      "fields": {
+        "summary": "A summary",
+        "fixVersions": [{"name": "1.2"}, {"name": "1.3"}],
+        "assignee": null,
+        "watchers": [{"emailAddress": "a@example.com"}, {"emailAddress": "b@example.com"}],
+      },
+    });
+    let exprs = Extract::try_from(HashMap::from([
+      ("version".to_string(), "fields.fixVersions[0].name".to_string()),
+      ("versions".to_string(), "fields.fixVersions[*].name".to_string()),
+      ("watcher".to_string(), "fields.watchers[0].emailAddress".to_string()),
+      ("assignee".to_string(), "fields.assignee.emailAddress".to_string()),
+    ])).unwrap();
+    let vars = extract(&data, &exprs).unwrap();
+    assert_eq!(3, vars.len());
+    assert_eq!("[TRENO-1] A summary (1.2; 1.2, 1.3; a@example.com)", format_value(&data, &vars, "issue", &["[{key}] {fields.summary} ({version}; {versions|join}; {watcher})"]).unwrap());
+    assert_eq!("[TRENO-1] Unassigned", format_value(&data, &vars, "issue", &["[{key}] {assignee}", "[{key}] Unassigned"]).unwrap());
+    assert!(Extract::try_from(HashMap::from([("bad".to_string(), "fields.[".to_string())])).is_err());
+    assert!(Default::load_data(&config::Config::new(), r#"
+example.com:
+  routes:
+    - name: issue
+      route: "/browse/{key}"
+      url: "https://api.example.com/issue/{key}"
+      format: "{key}"
+      extract:
+        bad: "fields.["
+"#.as_bytes()).is_err());
+  }
+
+  fn request_url(svc: &Default, link: &str) -> Option<String> {
+    match svc.request(&config::Config::new(), &url::Url::parse(link).unwrap()) {
+      Ok(req)  => Some(req.build().unwrap().url().to_string()),
+      Err(_)   => None,
+    }
+  }
+
+  #[test]
+  fn oembed_requests() {
+    let svc = Default::load_data(&config::Config::new(), r#"
+example.com:
+  routes:
+    - name: video
+      route: "/v/{id}"
+      oembed: "https://{domain}/oembed"
+      format: "{title}"
+"#.as_bytes()).unwrap();
+    assert_eq!(Some("https://example.com/oembed?url=https%3A%2F%2Fexample.com%2Fv%2F1%3Ft%3D5%26a%3Db&format=json".to_string()), request_url(&svc, "https://example.com/v/1?t=5&a=b"));
+
+    let svc = Default::load_default(&config::Config::new()).unwrap();
+    for (link, expect) in [
+      ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", "https://www.youtube.com/oembed?url=https%3A%2F%2Fwww.youtube.com%2Fwatch%3Fv%3DdQw4w9WgXcQ&format=json"),
+      ("https://youtu.be/dQw4w9WgXcQ", "https://www.youtube.com/oembed?url=https%3A%2F%2Fyoutu.be%2FdQw4w9WgXcQ&format=json"),
+      ("https://vimeo.com/76979871", "https://vimeo.com/api/oembed.json?url=https%3A%2F%2Fvimeo.com%2F76979871&format=json"),
+      ("https://open.spotify.com/track/4uLU6hMCjMI75M1A2tKUQC", "https://open.spotify.com/oembed?url=https%3A%2F%2Fopen.spotify.com%2Ftrack%2F4uLU6hMCjMI75M1A2tKUQC&format=json"),
+      ("https://soundcloud.com/forss/flickermood", "https://soundcloud.com/oembed?format=json&url=https%3A%2F%2Fsoundcloud.com%2Fforss%2Fflickermood"),
+      ("https://www.flickr.com/photos/bees/2341623661/", "https://www.flickr.com/services/oembed/?url=https%3A%2F%2Fwww.flickr.com%2Fphotos%2Fbees%2F2341623661%2F&format=json"),
+      ("https://github.com/bww/unfurl/pull/1", "https://api.github.com/repos/bww/unfurl/pulls/1"),
+    ] {
+      assert_eq!(Some(expect.to_string()), request_url(&svc, link), "Link: {}", link);
+    }
+    for link in ["https://vimeo.com/about", "https://soundcloud.com/discover/sets", "https://soundcloud.com/forss/sets"] {
+      assert_eq!(None, request_url(&svc, link), "Link: {}", link);
+    }
+  }
+
+  #[test]
+  fn invalid_requests() {
+    for (routes, expect) in [
+      ("url: \"https://example.com/{id}\"\n      oembed: \"https://example.com/oembed\"", "Route has both a url and an oembed endpoint: video"),
+      ("method: GET", "Route has neither a url nor an oembed endpoint: video"),
+      ("url: \"https://example.com/{id}\"\n      body: \"id={id}\"\n      graphql:\n        query: \"{ id }\"", "Route has both a body and a GraphQL query: video"),
+    ] {
+      let data = format!("example.com:\n  routes:\n    - name: video\n      route: \"/v/{{id}}\"\n      format: \"{{title}}\"\n      {}\n", routes);
+      match Default::load_data(&config::Config::new(), data.as_bytes()) {
+        Err(error::Error::Invalid(msg)) => assert_eq!(expect, msg),
+        res                             => panic!("Unexpected result: {:?}", res.map(|_| ())),
+      }
+    }
+  }
+
+  #[test]
+  fn post_requests() {
+    let svc = Default::load_data(&config::Config::new(), r#"
+example.com:
+  routes:
+    - name: search
+      route: "/search/{term}"
+      url: "https://api.example.com/search"
+      method: POST
+      body:
+        query: "{term}"
+        limit: 1
+      format: "{title}"
+    - name: raw
+      route: "/raw/{id}"
+      url: "https://api.example.com/raw"
+      method: POST
+      body: "id={id}"
+      format: "{title}"
+    - name: pr
+      route: "/{owner}/{repo}/pull/{num}"
+      url: "https://api.example.com/graphql"
+      graphql:
+        query: "query($owner: String!, $repo: String!, $num: Int!) { repository(owner: $owner, name: $repo) { pullRequest(number: $num) { title } } }"
+        variables:
+          owner: "{owner}"
+          repo: "{repo}"
+          num: "{num}"
+      format: "{repository.pullRequest.title}"
+"#.as_bytes()).unwrap();
+    let request = |link: &str| {
+      let req = svc.request(&config::Config::new(), &url::Url::parse(link).unwrap()).unwrap().build().unwrap();
+      let body = String::from_utf8(req.body().unwrap().as_bytes().unwrap().to_vec()).unwrap();
+      (req.method().to_string(), req.url().to_string(), body)
+    };
+    assert_eq!(("POST".to_string(), "https://api.example.com/search".to_string(), r#"{"limit":1,"query":"unfurl"}"#.to_string()), request("https://example.com/search/unfurl"));
+    assert_eq!(("POST".to_string(), "https://api.example.com/raw".to_string(), "id=5".to_string()), request("https://example.com/raw/5"));
+    let (method, url, body) = request("https://example.com/bww/unfurl/pull/12");
+    assert_eq!(("POST", "https://api.example.com/graphql"), (method.as_str(), url.as_str()));
+    assert_eq!(serde_json::json!({"owner": "bww", "repo": "unfurl", "num": 12}), serde_json::from_str::<serde_json::Value>(&body).unwrap()["variables"]);
+  }
+
+  #[test]
+  fn request_parts() {
+    let svc = Default::load_data(&config::Config::new(), r#"
+example.com:
+  routes:
+    - name: issue
+      route: "/browse/{key}"
+      url: "https://api.example.com/issue/{key}"
+      requests:
+        - name: transitions
+          url: "https://api.example.com/issue/{key}/transitions"
+        - name: comments
+          url: "https://api.example.com/issue/{key}/comments"
+      format:
+        - "{summary} ({transitions.0.name}, {comments.total} comments)"
+        - "{summary} ({transitions.0.name})"
+"#.as_bytes()).unwrap();
+    let conf = config::Config::new();
+    let link = url::Url::parse("https://example.com/browse/TRENO-1").unwrap();
+    let req = svc.request(&conf, &link).unwrap();
+    assert_eq!(Some("https://api.example.com/issue/TRENO-1/transitions".to_string()), req.part_url("transitions"));
+    assert_eq!(Some("https://api.example.com/issue/TRENO-1/comments".to_string()), req.part_url("comments"));
+
+    let rsp = fetch::Response::with_data(parse::Key::from(&link), Some("application/json"), r#"{"summary": "A summary"}"#)
+      .with_part("transitions", fetch::Response::with_data(parse::Key::from(&link), None, r#"[{"name": "Done"}]"#))
+      .with_part("comments", fetch::Response::with_data(parse::Key::from(&link), None, r#"{"total": 3}"#));
+    assert_eq!("A summary (Done, 3 comments)", svc.format(&conf, &link, &rsp).unwrap());
+    let rsp = fetch::Response::with_data(parse::Key::from(&link), Some("application/json"), r#"{"summary": "A summary"}"#)
+      .with_part("transitions", fetch::Response::with_data(parse::Key::from(&link), None, r#"[{"name": "Done"}]"#))
+      .with_part("comments", fetch::Response::with_data(parse::Key::from(&link), None, "Not JSON"));
+    assert_eq!("A summary (Done)", svc.format(&conf, &link, &rsp).unwrap());
+  }
+
+  #[test]
+  fn format_links() {
+    let svc = Default::load_data(&config::Config::new(), r#"
+example.com:
+  routes:
+    - name: issue
+      route: "/browse/{key}"
+      url: "https://api.example.com/issue/{key}"
+      format:
+        - "{summary}"
+        - "{link}"
+"#.as_bytes()).unwrap();
+    let conf = config::Config::new();
+    let link = url::Url::parse("https://example.com/browse/TRENO-1").unwrap();
+    let format = |data: &'static str| svc.format(&conf, &link, &fetch::Response::with_data(parse::Key::from(&link), Some("application/json"), data)).unwrap();
+    assert_eq!("A summary", format(r#"{"summary": "A summary"}"#));
+    assert_eq!("https://example.com/browse/TRENO-1", format(r#"{"title": "A title"}"#));
+    assert_eq!("https://example.com/browse/TRENO-1", format(r#"{"link": "https://api.example.com/issue/TRENO-1"}"#));
+  }
+
+  #[test]
+  fn load_formats() {
+    let svc = Default::load_data(&config::Config::new(), r#"
+example.com:
+  headers: {}
+  routes:
+    - name: one
+      route: "/one/{id}"
+      url: "https://api.example.com/one/{id}"
+      format: "{title}"
+    - name: many
+      route: "/many/{id}"
+      url: "https://api.example.com/many/{id}"
+      format:
+        - "{title} ({state})"
+        - "{title}"
+"#.as_bytes()).unwrap();
+    let dom = svc.domains.get("example.com").unwrap();
+    assert_eq!(&config::Format::Template("{title}".to_string()), dom.routes[0].format());
+    assert_eq!(&config::Format::Templates(vec!["{title} ({state})".to_string(), "{title}".to_string()]), dom.routes[1].format());
+  }
+
+  #[test]
+  fn authenticate_in_order() {
+    let routes = r#"
+atlassian.net:
+  config:
+    auth:
+      bearer: routes
+  routes:
+    - name: issue
+      route: "/browse/{key}"
+      url: "https://{domain}/rest/api/2/issue/{key}"
+      format: "{key}"
+example.com:
+  routes:
+    - name: item
+      route: "/items/{id}"
+      url: "https://api.example.com/items/{id}"
+      format: "{id}"
+"#;
+    let request = |conf: &str, link: &str| {
+      let conf = config::load_data(conf.as_bytes()).unwrap();
+      let svc = Default::load_data(&conf, routes.as_bytes()).unwrap();
+      let req = svc.request(&conf, &url::Url::parse(link).unwrap()).unwrap();
+      let auth = req.auth().map(|auth| auth.to_string());
+      let req = req.build().unwrap();
+      (req.headers().get("Authorization").map(|v| v.to_str().unwrap().to_string()), auth)
+    };
+    let conf = r#"
+services:
+  treno.atlassian.net:
+    auth:
+      bearer: host
+  other.atlassian.net:
+    format:
+      issue: "{key}!"
+  atlassian.net:
+    auth:
+      bearer: root
+"#;
+    assert_eq!((Some("Bearer host".to_string()), Some("the configuration for treno.atlassian.net".to_string())), request(conf, "https://treno.atlassian.net/browse/A-1"));
+    assert_eq!((Some("Bearer root".to_string()), Some("the configuration for atlassian.net".to_string())), request(conf, "https://other.atlassian.net/browse/A-1"));
+    assert_eq!((Some("Bearer routes".to_string()), Some("the routes for atlassian.net".to_string())), request("{}", "https://other.atlassian.net/browse/A-1"));
+    let conf = r#"
+services:
+  "*.atlassian.net":
+    auth:
+      bearer: wildcard
+  atlassian.net:
+    auth:
+      bearer: root
+"#;
+    assert_eq!((Some("Bearer wildcard".to_string()), Some("the configuration for *.atlassian.net".to_string())), request(conf, "https://treno.atlassian.net/browse/A-1"));
+    assert_eq!((None, None), request(conf, "https://example.com/items/1"));
+    let conf = r#"
+services:
+  treno.atlassian.net:
+    auth:
+      bearer: host
+default:
+  auth:
+    bearer: default
+"#;
+    assert_eq!((Some("Bearer host".to_string()), Some("the configuration for treno.atlassian.net".to_string())), request(conf, "https://treno.atlassian.net/browse/A-1"));
+    assert_eq!((Some("Bearer routes".to_string()), Some("the routes for atlassian.net".to_string())), request(conf, "https://other.atlassian.net/browse/A-1"));
+    assert_eq!((Some("Bearer default".to_string()), Some("the default configuration".to_string())), request(conf, "https://example.com/items/1"));
+  }
+
+  #[test]
+  fn find_hosts() {
+    let svc = Default::load_data(&config::Config::new(), r#"
+example.com:
+  routes: []
+"*.example.com":
+  routes: []
+atlassian.net:
+  routes: []
+"#.as_bytes()).unwrap();
+    let find = |link: &str| svc.find_host(&url::Url::parse(link).unwrap()).map(|dom| dom.name.as_str());
+    assert_eq!(Some("example.com"), find("https://example.com/a"));
+    assert_eq!(Some("*.example.com"), find("https://www.example.com/a"));
+    assert_eq!(Some("atlassian.net"), find("https://treno.atlassian.net/a"));
+    assert_eq!(None, find("https://example.org/a"));
+    assert_eq!(vec!["atlassian.net", "example.com"], svc.hosts());
+  }
+
+}
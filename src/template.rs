@@ -0,0 +1,633 @@
+use std::fmt;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde::Serialize;
+use serde_json::Value;
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+const ELLIPSIS: char = '…';
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+  Syntax(String),
+  Missing(String),
+  Invalid(String),
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Syntax(msg) => write!(f, "Template syntax error: {}", msg),
+      Self::Missing(path) => write!(f, "No value for: {}", path),
+      Self::Invalid(msg) => write!(f, "Template error: {}", msg),
+    }
+  }
+}
+
+/// A template, which renders values from a context with the syntax:
+///
+/// * `{path.to.value}` for a value; array elements are indexed like `{items.0}`,
+/// * `{value|filter|filter:arg,"another arg"}` to transform a value,
+/// * `{{ if value }}...{{ else }}...{{ endif }}`, also with `if not`,
+/// * `{{ for item in values }}...{{ endfor }}`, with `@index`, `@first` and `@last`,
+/// * `{{ with path.to.value as name }}...{{ endwith }}`, and
+/// * `\{` for a literal brace.
+///
+/// The available filters are `lower`, `upper`, `truncate:<length>`,
+/// `date:<format>`, `default:<value>`, `join:<separator>`,
+/// `pluralize:<singular>,<plural>` and `escape`.
+///
+/// Values are inserted as they are. Output is plain text or Markdown, not
+/// HTML, so unlike tinytemplate, which this replaces, values are only
+/// HTML-escaped by the `escape` filter.
+///
+/// The syntax is tinytemplate's, extended, rather than that of a general
+/// purpose engine like Handlebars or Jinja, whose `{{ value }}` syntax would
+/// break every existing route and configured format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+  nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+  Text(String),
+  Value(Expr),
+  If{expr: Expr, negate: bool, then: Vec<Node>, otherwise: Vec<Node>},
+  For{name: String, expr: Expr, body: Vec<Node>},
+  With{name: String, expr: Expr, body: Vec<Node>},
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Expr {
+  path: String,
+  filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+  Lower,
+  Upper,
+  Truncate(usize),
+  Date(String),
+  Default(String),
+  Join(String),
+  Pluralize(String, String),
+  Escape,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment<'a> {
+  Text(String),
+  Value(&'a str),
+  Tag(&'a str),
+}
+
+impl Template {
+  pub fn parse(text: &str) -> Result<Template, Error> {
+    let segs = segments(text)?;
+    let mut segs = segs.iter();
+    let (nodes, end) = parse_nodes(&mut segs)?;
+    match end {
+      Some(tag) => Err(Error::Syntax(format!("Unexpected: {{{{ {} }}}}", tag))),
+      None      => Ok(Template{nodes}),
+    }
+  }
+
+  pub fn render<S: Serialize>(&self, cxt: &S) -> Result<String, Error> {
+    match serde_json::to_value(cxt) {
+      Ok(cxt)  => self.render_value(&cxt),
+      Err(err) => Err(Error::Invalid(format!("Could not convert context: {}", err))),
+    }
+  }
+
+  pub fn render_value(&self, cxt: &Value) -> Result<String, Error> {
+    self.render_value_with(cxt, Vec::new())
+  }
+
+  /// Render the template with additional named values, which take precedence
+  /// over those in the context.
+  pub fn render_value_with(&self, cxt: &Value, vars: Vec<(String, Value)>) -> Result<String, Error> {
+    let mut out = String::new();
+    let mut scope = Scope{
+      root: cxt,
+      vars,
+    };
+    render_nodes(&mut out, &self.nodes, &mut scope)?;
+    Ok(out)
+  }
+}
+
+/// Parse and render a template in one go.
+pub fn render<S: Serialize>(text: &str, cxt: &S) -> Result<String, Error> {
+  Template::parse(text)?.render(cxt)
+}
+
+fn segments(text: &str) -> Result<Vec<Segment<'_>>, Error> {
+  let mut segs = Vec::new();
+  let mut lit = String::new();
+  let mut rest = text;
+  while let Some(x) = rest.find(['{', '\\']) {
+    lit.push_str(&rest[..x]);
+    let after = &rest[x + 1..];
+    if rest[x..].starts_with('\\') {
+      match after.chars().next() {
+        Some(c) if c == '{' || c == '}' || c == '\\' => {
+          lit.push(c);
+          rest = &after[1..];
+        },
+        _ => {
+          lit.push('\\');
+          rest = after;
+        },
+      }
+      continue;
+    }
+    if !lit.is_empty() {
+      segs.push(Segment::Text(std::mem::take(&mut lit)));
+    }
+    if let Some(tag) = after.strip_prefix('{') {
+      let y = match tag.find("}}") {
+        Some(y) => y,
+        None    => return Err(Error::Syntax(format!("Unclosed tag: {{{{{}", tag))),
+      };
+      segs.push(Segment::Tag(tag[..y].trim()));
+      rest = &tag[y + 2..];
+    } else {
+      let y = match after.find('}') {
+        Some(y) => y,
+        None    => return Err(Error::Syntax(format!("Unclosed value: {{{}", after))),
+      };
+      segs.push(Segment::Value(after[..y].trim()));
+      rest = &after[y + 1..];
+    }
+  }
+  lit.push_str(rest);
+  if !lit.is_empty() {
+    segs.push(Segment::Text(lit));
+  }
+  Ok(segs)
+}
+
+// Parse nodes until the end of the input or a tag which closes a block, which
+// is returned along with the nodes.
+fn parse_nodes<'a, I>(segs: &mut I) -> Result<(Vec<Node>, Option<&'a str>), Error>
+where
+  I: Iterator<Item = &'a Segment<'a>>,
+{
+  let mut nodes = Vec::new();
+  while let Some(seg) = segs.next() {
+    let tag = match seg {
+      Segment::Text(text)  => {
+        nodes.push(Node::Text(text.to_string()));
+        continue;
+      },
+      Segment::Value(expr) => {
+        nodes.push(Node::Value(parse_expr(expr)?));
+        continue;
+      },
+      Segment::Tag(tag) => *tag,
+    };
+    let (kw, arg) = match tag.split_once(char::is_whitespace) {
+      Some((kw, arg)) => (kw, arg.trim()),
+      None            => (tag, ""),
+    };
+    match kw {
+      "if" => {
+        let (negate, arg) = match arg.strip_prefix("not ") {
+          Some(arg) => (true, arg.trim()),
+          None      => (false, arg),
+        };
+        let expr = parse_expr(arg)?;
+        let (then, end) = parse_nodes(segs)?;
+        let otherwise = match end {
+          Some("endif") => Vec::new(),
+          Some("else")  => match parse_nodes(segs)? {
+            (otherwise, Some("endif")) => otherwise,
+            _                          => return Err(Error::Syntax("Expected: {{ endif }}".to_string())),
+          },
+          _ => return Err(Error::Syntax("Expected: {{ endif }}".to_string())),
+        };
+        nodes.push(Node::If{expr, negate, then, otherwise});
+      },
+      "for" => {
+        let (name, path) = match arg.split_once(" in ") {
+          Some((name, path)) => (name.trim(), path.trim()),
+          None               => return Err(Error::Syntax(format!("Expected: {{{{ for <name> in <value> }}}}, got: {{{{ {} }}}}", tag))),
+        };
+        let expr = parse_expr(path)?;
+        let body = match parse_nodes(segs)? {
+          (body, Some("endfor")) => body,
+          _                      => return Err(Error::Syntax("Expected: {{ endfor }}".to_string())),
+        };
+        nodes.push(Node::For{name: name.to_string(), expr, body});
+      },
+      "with" => {
+        let (path, name) = match arg.split_once(" as ") {
+          Some((path, name)) => (path.trim(), name.trim()),
+          None               => return Err(Error::Syntax(format!("Expected: {{{{ with <value> as <name> }}}}, got: {{{{ {} }}}}", tag))),
+        };
+        let expr = parse_expr(path)?;
+        let body = match parse_nodes(segs)? {
+          (body, Some("endwith")) => body,
+          _                       => return Err(Error::Syntax("Expected: {{ endwith }}".to_string())),
+        };
+        nodes.push(Node::With{name: name.to_string(), expr, body});
+      },
+      "else" | "endif" | "endfor" | "endwith" => return Ok((nodes, Some(kw))),
+      _ => return Err(Error::Syntax(format!("Unknown tag: {{{{ {} }}}}", tag))),
+    }
+  }
+  Ok((nodes, None))
+}
+
+fn parse_expr(text: &str) -> Result<Expr, Error> {
+  let (path, mut rest) = match text.find('|') {
+    Some(x) => (text[..x].trim(), Some(&text[x + 1..])),
+    None    => (text.trim(), None),
+  };
+  if path.is_empty() {
+    return Err(Error::Syntax(format!("Expected a value: {{{}}}", text)));
+  }
+  let mut filters = Vec::new();
+  while let Some(text) = rest {
+    let (filter, more) = parse_filter(text)?;
+    filters.push(filter);
+    rest = more;
+  }
+  Ok(Expr{
+    path: path.to_string(),
+    filters,
+  })
+}
+
+// Parse a filter and its arguments, returning the remaining text after the
+// next `|`, if there is one.
+fn parse_filter(text: &str) -> Result<(Filter, Option<&str>), Error> {
+  let x = text.find([':', '|']).unwrap_or(text.len());
+  let name = text[..x].trim();
+  let mut args: Vec<String> = Vec::new();
+  let mut rest = &text[x..];
+  if let Some(more) = rest.strip_prefix(':') {
+    rest = more;
+    loop {
+      let (arg, more) = parse_arg(rest)?;
+      args.push(arg);
+      match more.trim_start().chars().next() {
+        Some(',') => rest = &more.trim_start()[1..],
+        _         => {
+          rest = more.trim_start();
+          break;
+        },
+      }
+    }
+  }
+  let rest = match rest.chars().next() {
+    Some('|') => Some(&rest[1..]),
+    None      => None,
+    Some(_)   => return Err(Error::Syntax(format!("Unexpected: {}", rest))),
+  };
+  let arg = |i: usize, dflt: &str| args.get(i).cloned().unwrap_or_else(|| dflt.to_string());
+  let filter = match name {
+    "lower"     => Filter::Lower,
+    "upper"     => Filter::Upper,
+    "truncate"  => match arg(0, "").parse::<usize>() {
+      Ok(n)  => Filter::Truncate(n),
+      Err(_) => return Err(Error::Syntax(format!("Expected a length: truncate:{}", arg(0, "")))),
+    },
+    "date"      => Filter::Date(arg(0, DEFAULT_DATE_FORMAT)),
+    "default"   => Filter::Default(arg(0, "")),
+    "join"      => Filter::Join(arg(0, ", ")),
+    "pluralize" => Filter::Pluralize(arg(0, ""), arg(1, "s")),
+    "escape"    => Filter::Escape,
+    name        => return Err(Error::Syntax(format!("Unknown filter: {}", name))),
+  };
+  Ok((filter, rest))
+}
+
+// Parse a filter argument, which is either quoted or runs until the next
+// argument or filter.
+fn parse_arg(text: &str) -> Result<(String, &str), Error> {
+  let text = text.trim_start();
+  let q = match text.chars().next() {
+    Some(q) if q == '"' || q == '\'' => q,
+    _ => {
+      let x = text.find([',', '|']).unwrap_or(text.len());
+      return Ok((text[..x].trim().to_string(), &text[x..]));
+    },
+  };
+  let mut arg = String::new();
+  let mut chars = text.char_indices().skip(1);
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '\\' => if let Some((_, c)) = chars.next() {
+        arg.push(c);
+      },
+      c if c == q => return Ok((arg, &text[i + 1..])),
+      c => arg.push(c),
+    }
+  }
+  Err(Error::Syntax(format!("Unclosed argument: {}", text)))
+}
+
+struct Scope<'a> {
+  root: &'a Value,
+  vars: Vec<(String, Value)>,
+}
+
+impl<'a> Scope<'a> {
+  fn lookup(&self, path: &str) -> Option<Value> {
+    let mut parts = path.split('.');
+    let first = parts.next()?;
+    let mut val = if first == "@root" {
+      self.root
+    } else if let Some((_, val)) = self.vars.iter().rev().find(|(name, _)| name == first) {
+      val
+    } else {
+      lookup_in(self.root, first)?
+    };
+    for part in parts {
+      val = lookup_in(val, part)?;
+    }
+    Some(val.clone())
+  }
+}
+
+fn lookup_in<'a>(val: &'a Value, key: &str) -> Option<&'a Value> {
+  match val {
+    Value::Object(obj) => obj.get(key),
+    Value::Array(arr)  => key.parse::<usize>().ok().and_then(|i| arr.get(i)),
+    _                  => None,
+  }
+}
+
+fn render_nodes(out: &mut String, nodes: &[Node], scope: &mut Scope) -> Result<(), Error> {
+  for node in nodes {
+    match node {
+      Node::Text(text) => out.push_str(text),
+      Node::Value(expr) => out.push_str(&printable(&expr.path, &eval(expr, scope)?)?),
+      Node::If{expr, negate, then, otherwise} => {
+        let cond = match eval(expr, scope) {
+          Ok(val)                 => is_truthy(&val),
+          Err(Error::Missing(_))  => false,
+          Err(err)                => return Err(err),
+        };
+        render_nodes(out, if cond != *negate { then } else { otherwise }, scope)?;
+      },
+      Node::For{name, expr, body} => {
+        let vals = match eval(expr, scope)? {
+          Value::Array(vals) => vals,
+          _                  => return Err(Error::Invalid(format!("Not an array: {}", expr.path))),
+        };
+        let n = vals.len();
+        for (i, val) in vals.into_iter().enumerate() {
+          scope.vars.push(("@index".to_string(), Value::from(i)));
+          scope.vars.push(("@first".to_string(), Value::from(i == 0)));
+          scope.vars.push(("@last".to_string(), Value::from(i + 1 == n)));
+          scope.vars.push((name.to_string(), val));
+          let res = render_nodes(out, body, scope);
+          scope.vars.truncate(scope.vars.len() - 4);
+          res?;
+        }
+      },
+      Node::With{name, expr, body} => {
+        scope.vars.push((name.to_string(), eval(expr, scope)?));
+        let res = render_nodes(out, body, scope);
+        scope.vars.pop();
+        res?;
+      },
+    }
+  }
+  Ok(())
+}
+
+fn eval(expr: &Expr, scope: &Scope) -> Result<Value, Error> {
+  let mut val = scope.lookup(&expr.path);
+  for filter in &expr.filters {
+    val = match (filter, val) {
+      (Filter::Default(dflt), None)  => Some(Value::from(dflt.as_str())),
+      (Filter::Default(dflt), Some(val)) => if val.is_null() || val == "" {
+        Some(Value::from(dflt.as_str()))
+      } else {
+        Some(val)
+      },
+      (_, None)           => None,
+      (filter, Some(val)) => Some(apply(filter, &expr.path, val)?),
+    };
+  }
+  match val {
+    Some(val) => Ok(val),
+    None      => Err(Error::Missing(expr.path.to_string())),
+  }
+}
+
+fn apply(filter: &Filter, path: &str, val: Value) -> Result<Value, Error> {
+  Ok(match filter {
+    Filter::Lower => Value::from(printable(path, &val)?.to_lowercase()),
+    Filter::Upper => Value::from(printable(path, &val)?.to_uppercase()),
+    Filter::Truncate(n) => {
+      let text = printable(path, &val)?;
+      if text.chars().count() <= *n {
+        Value::from(text)
+      } else {
+        let mut text: String = text.chars().take(n.saturating_sub(1)).collect();
+        text.truncate(text.trim_end().len());
+        text.push(ELLIPSIS);
+        Value::from(text)
+      }
+    },
+    Filter::Date(format) => match parse_date(&val) {
+      Some(date) => Value::from(date.format(format).to_string()),
+      None       => return Err(Error::Invalid(format!("Not a date: {}", path))),
+    },
+    Filter::Default(_) => val, // handled by eval, which also sees missing values
+    Filter::Join(sep) => match val {
+      Value::Array(vals) => {
+        let vals: Result<Vec<String>, Error> = vals.iter().map(|v| printable(path, v)).collect();
+        Value::from(vals?.join(sep))
+      },
+      val => val,
+    },
+    Filter::Pluralize(one, many) => {
+      let n = match &val {
+        Value::Number(n) => n.as_f64(),
+        Value::Array(a)  => Some(a.len() as f64),
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        _                => None,
+      };
+      match n {
+        Some(n) => Value::from(if n == 1.0 { one.as_str() } else { many.as_str() }),
+        None    => return Err(Error::Invalid(format!("Not a number: {}", path))),
+      }
+    },
+    Filter::Escape => Value::from(escape_html(&printable(path, &val)?)),
+  })
+}
+
+fn escape_html(text: &str) -> String {
+  let mut res = String::with_capacity(text.len());
+  for c in text.chars() {
+    match c {
+      '&'  => res.push_str("&amp;"),
+      '<'  => res.push_str("&lt;"),
+      '>'  => res.push_str("&gt;"),
+      '"'  => res.push_str("&quot;"),
+      '\'' => res.push_str("&#39;"),
+      c    => res.push(c),
+    }
+  }
+  res
+}
+
+// Dates are accepted as RFC 3339 and related ISO 8601 forms, like those
+// produced by GitHub and Jira, or as a Unix timestamp in seconds.
+fn parse_date(val: &Value) -> Option<NaiveDateTime> {
+  let text = match val {
+    Value::String(text) => text.trim(),
+    Value::Number(n)    => return DateTime::from_timestamp(n.as_i64()?, 0).map(|d| d.naive_utc()),
+    _                   => return None,
+  };
+  if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+    return Some(date.naive_utc());
+  }
+  if let Ok(date) = DateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f%z") {
+    return Some(date.naive_utc());
+  }
+  if let Ok(date) = NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f") {
+    return Some(date);
+  }
+  NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+fn printable(path: &str, val: &Value) -> Result<String, Error> {
+  match val {
+    Value::Null      => Ok(String::new()),
+    Value::Bool(v)   => Ok(v.to_string()),
+    Value::Number(v) => Ok(v.to_string()),
+    Value::String(v) => Ok(v.to_string()),
+    _                => Err(Error::Invalid(format!("Not a printable value: {}", path))),
+  }
+}
+
+fn is_truthy(val: &Value) -> bool {
+  match val {
+    Value::Null      => false,
+    Value::Bool(v)   => *v,
+    Value::Number(v) => v.as_f64().is_some_and(|v| v != 0.0),
+    Value::String(v) => !v.is_empty(),
+    Value::Array(v)  => !v.is_empty(),
+    Value::Object(v) => !v.is_empty(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn render_json(text: &str, cxt: Value) -> Result<String, Error> {
+    Template::parse(text)?.render_value(&cxt)
+  }
+
+  #[test]
+  fn render_values() {
+    let cxt = json!({
+      "title": "Fix <everything> & more",
+      "number": 5,
+      "draft": false,
+      "user": {"login": "bww"},
+      "labels": [{"name": "bug"}, {"name": "ui"}],
+    });
+    // values are inserted as they are unless they're escaped
+    assert_eq!("Fix <everything> & more (PR #5)", render_json("{title} (PR #{number})", cxt.clone()).unwrap());
+    assert_eq!("Fix &lt;everything&gt; &amp; more", render_json("{title|escape}", cxt.clone()).unwrap());
+    assert_eq!("bww / bug / false", render_json("{ user.login } / {labels.0.name} / {draft}", cxt.clone()).unwrap());
+    assert_eq!("{literal} \\", render_json("\\{literal\\} \\\\", cxt.clone()).unwrap());
+    assert_eq!("a \\ b", render_json("a \\ b", cxt.clone()).unwrap());
+    assert_eq!(Err(Error::Missing("missing".to_string())), render_json("{missing}", cxt.clone()));
+    assert_eq!(Err(Error::Missing("user.missing".to_string())), render_json("{user.missing}", cxt.clone()));
+    assert!(matches!(render_json("{user}", cxt.clone()), Err(Error::Invalid(_))));
+  }
+
+  #[test]
+  fn render_vars() {
+    let cxt = json!({"title": "A title", "key": "A-1"});
+    let tmpl = Template::parse("{key}: {title} ({version.name})").unwrap();
+    let vars = vec![
+      ("key".to_string(), json!("B-2")),
+      ("version".to_string(), json!({"name": "1.0"})),
+    ];
+    assert_eq!("B-2: A title (1.0)", tmpl.render_value_with(&cxt, vars).unwrap());
+    assert_eq!("A title", Template::parse("{t}").unwrap().render_value_with(&json!([1, 2]), vec![("t".to_string(), json!("A title"))]).unwrap());
+  }
+
+  #[test]
+  fn render_blocks() {
+    let cxt = json!({
+      "draft": true,
+      "state": "",
+      "labels": [{"name": "bug"}, {"name": "ui"}, {"name": "ux"}],
+      "user": {"login": "bww"},
+    });
+    assert_eq!("Draft", render_json("{{ if draft }}Draft{{ else }}Ready{{ endif }}", cxt.clone()).unwrap());
+    assert_eq!("Ready", render_json("{{ if not draft }}Draft{{ else }}Ready{{ endif }}", cxt.clone()).unwrap());
+    assert_eq!("", render_json("{{ if state }}{state}{{ endif }}", cxt.clone()).unwrap());
+    assert_eq!("none", render_json("{{ if user.missing }}some{{ else }}none{{ endif }}", cxt.clone()).unwrap());
+    assert_eq!("0:bug, 1:ui, 2:ux.", render_json("{{ for l in labels }}{@index}:{l.name}{{ if @last }}.{{ else }}, {{ endif }}{{ endfor }}", cxt.clone()).unwrap());
+    assert_eq!("bww bww", render_json("{{ with user as u }}{u.login} {@root.user.login}{{ endwith }}", cxt.clone()).unwrap());
+    assert!(matches!(render_json("{{ if draft }}Draft", cxt.clone()), Err(Error::Syntax(_))));
+    assert!(matches!(render_json("{{ endif }}", cxt.clone()), Err(Error::Syntax(_))));
+    assert!(matches!(render_json("{{ for l labels }}{{ endfor }}", cxt.clone()), Err(Error::Syntax(_))));
+    assert!(matches!(render_json("{{ unknown }}", cxt.clone()), Err(Error::Syntax(_))));
+    assert!(matches!(render_json("{unclosed", cxt.clone()), Err(Error::Syntax(_))));
+  }
+
+  #[test]
+  fn render_filters() {
+    let cxt = json!({
+      "title": "A rather long title for a pull request",
+      "state": "open",
+      "created": "2023-11-02T17:04:51Z",
+      "jira": "2023-11-02T17:04:51.123+0000",
+      "day": "2023-11-02",
+      "epoch": 1698944691,
+      "empty": "",
+      "none": null,
+      "names": ["a", "b", "c"],
+      "one": 1,
+      "many": 3,
+    });
+    for (text, expect) in [
+      ("{title|truncate:16}", "A rather long t…"),
+      ("{title|truncate:9}", "A rather…"),
+      ("{state|truncate:4}", "open"),
+      ("{title|truncate:60} ({state|upper})", "A rather long title for a pull request (OPEN)"),
+      ("{state|upper|lower}", "open"),
+      ("{created|date}", "2023-11-02"),
+      ("{created|date:\"%b %e, %Y %H:%M\"}", "Nov  2, 2023 17:04"),
+      ("{jira|date:%Y/%m/%d}", "2023/11/02"),
+      ("{day|date:'%d.%m.%Y'}", "02.11.2023"),
+      ("{epoch|date}", "2023-11-02"),
+      ("{missing|default:n/a}", "n/a"),
+      ("{empty|default:\"none, really\"}", "none, really"),
+      ("{none|default:-}", "-"),
+      ("{state|default:n/a}", "open"),
+      ("{missing|default|upper}", ""),
+      ("{names|join}", "a, b, c"),
+      ("{names|join:\" | \"}", "a | b | c"),
+      ("{one} issue{one|pluralize}", "1 issue"),
+      ("{many} issue{many|pluralize}", "3 issues"),
+      ("{many} {many|pluralize:child,children}", "3 children"),
+      ("{names|pluralize:\"name\", \"names\"}", "names"),
+    ] {
+      assert_eq!(Ok(expect.to_string()), render_json(text, cxt.clone()), "Template: {}", text);
+    }
+    assert!(matches!(render_json("{state|date}", cxt.clone()), Err(Error::Invalid(_))));
+    assert!(matches!(render_json("{state|pluralize}", cxt.clone()), Err(Error::Invalid(_))));
+    assert!(matches!(render_json("{state|unknown}", cxt.clone()), Err(Error::Syntax(_))));
+    assert!(matches!(render_json("{state|truncate:x}", cxt.clone()), Err(Error::Syntax(_))));
+    assert!(matches!(render_json("{state|default:\"x}", cxt.clone()), Err(Error::Syntax(_))));
+    assert_eq!(Err(Error::Missing("missing".to_string())), render_json("{missing|upper}", cxt.clone()));
+  }
+
+}
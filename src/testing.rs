@@ -0,0 +1,86 @@
+//! A server for tests, which answers requests with responses given in
+//! advance and reports each request it receives.
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+/// A request received by the server.
+#[derive(Debug)]
+pub struct Received {
+  pub path: String,
+  pub head: String,
+  pub body: String,
+}
+
+/// Serve each of the responses in turn, one per connection, and the last one
+/// to any connections after that. This produces the address served on, like
+/// `127.0.0.1:8080`, and a receiver for each request; a request is reported
+/// before it's answered.
+pub fn serve(rsps: Vec<String>) -> (String, mpsc::Receiver<Received>) {
+  serve_with(move |n, _| rsps[n.min(rsps.len() - 1)].clone())
+}
+
+/// Serve each connection on a thread of its own, answering the nth request
+/// with the response produced for it, which may take as long as it likes.
+pub fn serve_with<F>(respond: F) -> (String, mpsc::Receiver<Received>)
+where
+  F: Fn(usize, &Received) -> String + Send + Sync + 'static,
+{
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let addr = listener.local_addr().unwrap().to_string();
+  let (tx, rx) = mpsc::channel();
+  let respond = Arc::new(respond);
+  thread::spawn(move || {
+    for (n, conn) in listener.incoming().enumerate() {
+      let mut conn = match conn {
+        Ok(conn) => conn,
+        Err(_)   => continue,
+      };
+      let (tx, respond) = (tx.clone(), respond.clone());
+      thread::spawn(move || {
+        let req = match read(&mut conn) {
+          Some(req) => req,
+          None      => return,
+        };
+        let rsp = respond(n, &req);
+        let _ = tx.send(req);
+        let _ = conn.write_all(rsp.as_bytes());
+      });
+    }
+  });
+  (addr, rx)
+}
+
+/// A response with a status and a JSON body.
+pub fn json(status: u16, data: &str) -> String {
+  format!("HTTP/1.1 {} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, data.len(), data)
+}
+
+// Read a request, including its body.
+fn read(conn: &mut TcpStream) -> Option<Received> {
+  let mut req = Vec::new();
+  let mut buf = [0u8; 4096];
+  loop {
+    let n = conn.read(&mut buf).ok()?;
+    req.extend_from_slice(&buf[..n]);
+    let text = String::from_utf8_lossy(&req).to_string();
+    match text.split_once("\r\n\r\n") {
+      Some((head, body)) => {
+        let len = head.lines()
+          .find_map(|l| l.to_lowercase().strip_prefix("content-length: ").and_then(|v| v.trim().parse::<usize>().ok()))
+          .unwrap_or(0);
+        if body.len() >= len || n == 0 {
+          return Some(Received{
+            path: head.split(' ').nth(1).unwrap_or("").to_string(),
+            head: head.to_string(),
+            body: body.to_string(),
+          });
+        }
+      },
+      None if n == 0 => return None,
+      None           => {},
+    }
+  }
+}